@@ -0,0 +1,150 @@
+// address.rs
+// Account addresses: an account is named by its ed25519 public key and shown
+// as a bech32m string with the `xzn` prefix, e.g. `xzn1...`. The checksum
+// makes parsing reject typos instead of silently naming a new account.
+
+use bech32::primitives::decode::CheckedHrpstring;
+use bech32::{Bech32m, Hrp};
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+pub const ADDRESS_HRP: Hrp = Hrp::parse_unchecked("xzn");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address(pub [u8; 32]);
+
+impl Address {
+    pub fn from_public_key(key: &VerifyingKey) -> Self {
+        Address(key.to_bytes())
+    }
+
+    // None if the bytes are not a valid ed25519 point, in which case nobody
+    // can sign for this address
+    pub fn public_key(&self) -> Option<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.0).ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    Encoding(String),
+    WrongPrefix(String),
+    WrongLength(usize),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Encoding(e) => write!(f, "invalid address encoding: {}", e),
+            AddressError::WrongPrefix(hrp) => {
+                write!(f, "address prefix must be `{}`, got `{}`", ADDRESS_HRP, hrp)
+            }
+            AddressError::WrongLength(len) => {
+                write!(f, "address must hold 32 bytes, got {}", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, ADDRESS_HRP, &self.0).map_err(|_| fmt::Error)
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let checked = CheckedHrpstring::new::<Bech32m>(s)
+            .map_err(|e| AddressError::Encoding(e.to_string()))?;
+        if checked.hrp() != ADDRESS_HRP {
+            return Err(AddressError::WrongPrefix(checked.hrp().to_string()));
+        }
+        let bytes: Vec<u8> = checked.byte_iter().collect();
+        let len = bytes.len();
+        let bytes = <[u8; 32]>::try_from(bytes).map_err(|_| AddressError::WrongLength(len))?;
+        Ok(Address(bytes))
+    }
+}
+
+// Human-readable formats (JSON) carry the bech32m string, binary formats the
+// raw 32 bytes
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(Address)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let address = Address([0xab; 32]);
+        let encoded = address.to_string();
+        assert!(encoded.starts_with("xzn1"));
+        assert_eq!(encoded.parse::<Address>().unwrap(), address);
+        assert_eq!(encoded.to_uppercase().parse::<Address>().unwrap(), address);
+    }
+
+    #[test]
+    fn test_rejects_typos() {
+        let encoded = Address([7; 32]).to_string();
+        let mut chars: Vec<char> = encoded.chars().collect();
+        let i = chars.len() - 10;
+        chars[i] = if chars[i] == 'q' { 'p' } else { 'q' };
+        let typo: String = chars.into_iter().collect();
+        assert!(matches!(
+            typo.parse::<Address>(),
+            Err(AddressError::Encoding(_))
+        ));
+        assert!("ckelley.eth".parse::<Address>().is_err());
+        assert!("genesis".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_rejects_wrong_prefix_and_length() {
+        let other = bech32::encode::<Bech32m>(Hrp::parse("btc").unwrap(), &[1; 32]).unwrap();
+        assert_eq!(
+            other.parse::<Address>(),
+            Err(AddressError::WrongPrefix("btc".into()))
+        );
+        let short = bech32::encode::<Bech32m>(ADDRESS_HRP, &[1; 20]).unwrap();
+        assert_eq!(short.parse::<Address>(), Err(AddressError::WrongLength(20)));
+        // Plain bech32 checksums are not accepted
+        let legacy = bech32::encode::<bech32::Bech32>(ADDRESS_HRP, &[1; 32]).unwrap();
+        assert!(legacy.parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_serde_formats() {
+        let address = Address([3; 32]);
+        let json = serde_json::to_string(&address).unwrap();
+        assert_eq!(json, format!("\"{}\"", address));
+        assert_eq!(serde_json::from_str::<Address>(&json).unwrap(), address);
+        let bin = bincode::serialize(&address).unwrap();
+        assert_eq!(bin.len(), 32);
+        assert_eq!(bincode::deserialize::<Address>(&bin).unwrap(), address);
+    }
+}
@@ -0,0 +1,544 @@
+// block_tree.rs
+// Every known block, side branches included, and the fork-choice rules that
+// decide which branch is canonical.
+
+use crate::receipt::Receipt;
+use crate::{Block, ChangeSet};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct BlockTree {
+    blocks: HashMap<String, Block>,
+    children: HashMap<String, Vec<String>>,
+    // Summed fork-choice weight of each block's branch, genesis excluded
+    weights: HashMap<String, u64>,
+    // Hash of the latest block that may never be reverted
+    finalized: String,
+}
+
+impl BlockTree {
+    pub fn new(genesis: &Block) -> Self {
+        BlockTree {
+            blocks: HashMap::from([(genesis.hash.clone(), genesis.clone())]),
+            children: HashMap::new(),
+            weights: HashMap::from([(genesis.hash.clone(), 0)]),
+            finalized: genesis.hash.clone(),
+        }
+    }
+
+    // The caller checks that the parent is known and the block valid.
+    // `weight` is what the block adds to its branch under fork choice.
+    pub fn insert(&mut self, block: Block, weight: u64) {
+        if self.contains(&block.hash) {
+            return;
+        }
+        let parent = self.weight(&block.header.previous_hash).unwrap_or(0);
+        self.weights
+            .insert(block.hash.clone(), parent.saturating_add(weight));
+        self.children
+            .entry(block.header.previous_hash.clone())
+            .or_default()
+            .push(block.hash.clone());
+        self.blocks.insert(block.hash.clone(), block);
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    // Weight of the branch ending at `hash`, summed as blocks were inserted
+    pub fn weight(&self, hash: &str) -> Option<u64> {
+        self.weights.get(hash).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    // The ancestor of `hash` at `height`, or the block itself
+    pub fn ancestor_at(&self, hash: &str, height: u64) -> Option<&Block> {
+        let mut block = self.blocks.get(hash)?;
+        while block.header.index > height {
+            block = self.blocks.get(&block.header.previous_hash)?;
+        }
+        (block.header.index == height).then_some(block)
+    }
+
+    pub fn is_descendant(&self, hash: &str, ancestor: &str) -> bool {
+        self.blocks.get(ancestor).is_some_and(|ancestor| {
+            self.ancestor_at(hash, ancestor.header.index)
+                .is_some_and(|block| block.hash == ancestor.hash)
+        })
+    }
+
+    // Blocks from genesis up to and including `tip`
+    pub fn branch(&self, tip: &str) -> Vec<Block> {
+        let mut branch = Vec::new();
+        let mut next = self.blocks.get(tip);
+        while let Some(block) = next {
+            branch.push(block.clone());
+            next = self.blocks.get(&block.header.previous_hash);
+        }
+        branch.reverse();
+        branch
+    }
+
+    pub fn finalized(&self) -> &str {
+        &self.finalized
+    }
+
+    pub fn set_finalized(&mut self, hash: &str) {
+        self.finalized = hash.to_string();
+    }
+
+    // Forgets every child of `parent` except `keep`, with everything built
+    // on them
+    pub fn remove_siblings(&mut self, parent: &str, keep: &str) {
+        let children = self.children.get(parent).cloned().unwrap_or_default();
+        for child in children.iter().filter(|child| *child != keep) {
+            self.remove_subtree(child);
+        }
+    }
+
+    // Forgets `hash` and everything built on it
+    pub fn remove_subtree(&mut self, hash: &str) {
+        let Some(block) = self.blocks.remove(hash) else {
+            return;
+        };
+        self.weights.remove(hash);
+        if let Some(siblings) = self.children.get_mut(&block.header.previous_hash) {
+            siblings.retain(|child| child != hash);
+        }
+        for child in self.children.remove(hash).unwrap_or_default() {
+            self.remove_subtree(&child);
+        }
+    }
+}
+
+// Decides between the canonical branch and a competing one
+pub trait ForkChoice: fmt::Debug + Send + Sync {
+    // True if the branch ending at `candidate` should replace the canonical
+    // branch ending at `current`. Ties should keep `current`, so nodes do not
+    // flip between equally good branches.
+    fn prefer(&self, tree: &BlockTree, candidate: &Block, current: &Block) -> bool;
+
+    // What a block adds to its branch. The tree sums it when the block is
+    // inserted, so rules comparing whole branches need not walk them.
+    fn block_weight(&self, _block: &Block) -> u64 {
+        1
+    }
+}
+
+// The branch with the most blocks wins
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LongestChain;
+
+impl ForkChoice for LongestChain {
+    fn prefer(&self, _tree: &BlockTree, candidate: &Block, current: &Block) -> bool {
+        candidate.header.index > current.header.index
+    }
+}
+
+// The branch with the greatest total weight wins. Blocks carry no difficulty,
+// so the weight of a block is supplied by the caller.
+#[derive(Debug, Clone, Copy)]
+pub struct HeaviestChain {
+    pub weight: fn(&Block) -> u64,
+}
+
+impl HeaviestChain {
+    pub fn new(weight: fn(&Block) -> u64) -> Self {
+        HeaviestChain { weight }
+    }
+
+    // Weight of the branch ending at `tip`, genesis excluded. Read from the
+    // tree, which must have been built with this rule's block_weight.
+    pub fn branch_weight(&self, tree: &BlockTree, tip: &str) -> u64 {
+        tree.weight(tip).unwrap_or(0)
+    }
+}
+
+impl ForkChoice for HeaviestChain {
+    fn prefer(&self, tree: &BlockTree, candidate: &Block, current: &Block) -> bool {
+        self.branch_weight(tree, &candidate.hash) > self.branch_weight(tree, &current.hash)
+    }
+
+    fn block_weight(&self, block: &Block) -> u64 {
+        (self.weight)(block)
+    }
+}
+
+// Only branches through the finalized block are eligible; among those the
+// inner rule decides
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FinalizedFirst<F>(pub F);
+
+impl<F: ForkChoice> ForkChoice for FinalizedFirst<F> {
+    fn prefer(&self, tree: &BlockTree, candidate: &Block, current: &Block) -> bool {
+        let finalized = tree.finalized();
+        match (
+            tree.is_descendant(&candidate.hash, finalized),
+            tree.is_descendant(&current.hash, finalized),
+        ) {
+            (false, _) => false,
+            (true, false) => true,
+            (true, true) => self.0.prefer(tree, candidate, current),
+        }
+    }
+
+    fn block_weight(&self, block: &Block) -> u64 {
+        self.0.block_weight(block)
+    }
+}
+
+// How the canonical chain moved after an import
+#[derive(Debug, Clone, Default)]
+pub struct ChainUpdate {
+    // Blocks that left the canonical chain, old tip first
+    pub reverted: Vec<Block>,
+    // Blocks that joined it, lowest first
+    pub applied: Vec<Block>,
+    // The receipts and state changes of each applied block, in the same
+    // order, so they can be stored without executing the blocks again
+    pub executed: Vec<(Vec<Receipt>, ChangeSet)>,
+}
+
+impl ChainUpdate {
+    pub fn is_reorg(&self) -> bool {
+        !self.reverted.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::DEVNET_CHAIN_ID;
+    use crate::{Address, BlockError, Blockchain, MAX_FORK_DEPTH, StateStore, Transaction, Wallet};
+
+    fn alice() -> Wallet {
+        Wallet::from_secret_key(&[1; 32])
+    }
+
+    fn genesis_state() -> StateStore {
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.allocate(alice().address, 100).unwrap();
+        state
+    }
+
+    fn transfer(to: u8, amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction {
+            chain_id: DEVNET_CHAIN_ID.into(),
+            from: alice().address,
+            to: Address([to; 32]),
+            amount,
+            fee: 1,
+            nonce,
+            signature: None,
+        };
+        alice().sign_transaction(&mut tx);
+        tx
+    }
+
+    // A valid child of `parent`, whose post-state is `state`; `proposer`
+    // tells sibling blocks apart
+    fn child(
+        parent: &Block,
+        state: &mut StateStore,
+        txs: Vec<Transaction>,
+        proposer: &str,
+    ) -> Block {
+        let mut overlay = state.overlay();
+        let receipts: Vec<_> = txs.iter().map(|tx| overlay.apply_tx(tx).unwrap()).collect();
+        let state_root = overlay.state_root();
+        let changes = overlay.into_changes();
+        state.commit(changes);
+        Block::new(
+            parent.header.index + 1,
+            parent.header.timestamp + 1,
+            txs,
+            &receipts,
+            parent.hash.clone(),
+            state_root,
+            proposer.into(),
+        )
+    }
+
+    #[test]
+    fn test_extends_tip_and_keeps_side_branches() {
+        let genesis = genesis_state();
+        let mut chain = Blockchain::new();
+        let mut state = genesis.clone();
+
+        let mut a_state = genesis.clone();
+        let a1 = child(
+            &chain.chain[0],
+            &mut a_state,
+            vec![transfer(0xbb, 10, 0)],
+            "a",
+        );
+        let update = chain.import_block(a1.clone(), &mut state).unwrap();
+        assert!(!update.is_reorg());
+        assert_eq!(update.applied[0].hash, a1.hash);
+        assert_eq!(state, a_state);
+
+        // Same height: stored, but the first-seen branch stays canonical
+        let mut b_state = genesis.clone();
+        let b1 = child(
+            &chain.chain[0],
+            &mut b_state,
+            vec![transfer(0xcc, 20, 0)],
+            "b",
+        );
+        let update = chain.import_block(b1.clone(), &mut state).unwrap();
+        assert!(update.applied.is_empty());
+        assert_eq!(chain.latest_hash(), a1.hash);
+        assert!(chain.tree.contains(&b1.hash));
+        assert_eq!(chain.tree.len(), 3);
+        assert_eq!(state, a_state);
+
+        // Importing a known block is a no-op
+        assert!(
+            chain
+                .import_block(b1, &mut state)
+                .unwrap()
+                .applied
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_longer_branch_causes_reorg() {
+        let genesis = genesis_state();
+        let mut chain = Blockchain::new();
+        let mut state = genesis.clone();
+        let root = chain.chain[0].clone();
+
+        let mut a_state = genesis.clone();
+        let a1 = child(&root, &mut a_state, vec![transfer(0xbb, 10, 0)], "a");
+        chain.import_block(a1.clone(), &mut state).unwrap();
+
+        let mut b_state = genesis.clone();
+        let b1 = child(&root, &mut b_state, vec![transfer(0xcc, 20, 0)], "b");
+        let b2 = child(&b1, &mut b_state, vec![transfer(0xcc, 5, 1)], "b");
+        chain.import_block(b1.clone(), &mut state).unwrap();
+        let update = chain.import_block(b2.clone(), &mut state).unwrap();
+
+        assert!(update.is_reorg());
+        let hashes = |blocks: &[Block]| blocks.iter().map(|b| b.hash.clone()).collect::<Vec<_>>();
+        assert_eq!(hashes(&update.reverted), vec![a1.hash.clone()]);
+        assert_eq!(
+            hashes(&update.applied),
+            vec![b1.hash.clone(), b2.hash.clone()]
+        );
+        assert_eq!(hashes(&chain.chain), vec![root.hash, b1.hash, b2.hash]);
+        assert!(chain.is_valid_chain());
+        // State follows the new branch: A1's transfer is undone
+        assert_eq!(state, b_state);
+        assert_eq!(state.balance(&Address([0xcc; 32])), 25);
+        assert!(!state.balances().contains_key(&Address([0xbb; 32])));
+        assert_eq!(state.state_root(), chain.tip().header.state_root);
+        // The old branch is kept and wins again if it grows longer
+        assert!(chain.tree.contains(&a1.hash));
+        let a2 = child(&a1, &mut a_state, vec![transfer(0xbb, 1, 1)], "a");
+        let a3 = child(&a2, &mut a_state, vec![], "a");
+        chain.import_block(a2, &mut state).unwrap();
+        let update = chain.import_block(a3.clone(), &mut state).unwrap();
+        assert_eq!(update.reverted.len(), 2);
+        assert_eq!(chain.latest_hash(), a3.hash);
+        assert_eq!(state, a_state);
+    }
+
+    #[test]
+    fn test_rollback_takes_back_an_import() {
+        let genesis = genesis_state();
+        let mut chain = Blockchain::new();
+        let mut state = genesis.clone();
+        let root = chain.chain[0].clone();
+
+        let mut a_state = genesis.clone();
+        let a1 = child(&root, &mut a_state, vec![transfer(0xbb, 10, 0)], "a");
+        let update = chain.import_block(a1.clone(), &mut state).unwrap();
+        assert_eq!(update.executed.len(), 1);
+        assert_eq!(update.executed[0].0.len(), 1);
+        chain.rollback(&update, &mut state);
+        assert_eq!(chain.latest_hash(), root.hash);
+        assert_eq!(state, genesis);
+        assert!(!chain.tree.contains(&a1.hash));
+        chain.import_block(a1.clone(), &mut state).unwrap();
+
+        let mut b_state = genesis.clone();
+        let b1 = child(&root, &mut b_state, vec![transfer(0xcc, 20, 0)], "b");
+        let b2 = child(&b1, &mut b_state, vec![], "b");
+        chain.import_block(b1.clone(), &mut state).unwrap();
+        let update = chain.import_block(b2.clone(), &mut state).unwrap();
+        assert!(update.is_reorg());
+        chain.rollback(&update, &mut state);
+        assert_eq!(chain.latest_hash(), a1.hash);
+        assert_eq!(state, a_state);
+        // Only the block that caused the reorg is forgotten
+        assert!(chain.tree.contains(&b1.hash));
+        assert!(!chain.tree.contains(&b2.hash));
+        chain.import_block(b2.clone(), &mut state).unwrap();
+        assert_eq!(chain.latest_hash(), b2.hash);
+        assert_eq!(state, b_state);
+    }
+
+    #[test]
+    fn test_heaviest_chain_can_prefer_a_shorter_branch() {
+        // Every block counts once, plus once per transaction it carries
+        let by_txs = HeaviestChain::new(|block| 1 + block.transactions.len() as u64);
+        let genesis = genesis_state();
+        let mut chain = Blockchain::with_fork_choice(by_txs);
+        let mut state = genesis.clone();
+        let root = chain.chain[0].clone();
+
+        let mut a_state = genesis.clone();
+        let a1 = child(&root, &mut a_state, vec![transfer(0xbb, 10, 0)], "a");
+        let a2 = child(&a1, &mut a_state, vec![], "a");
+        chain.import_block(a1, &mut state).unwrap();
+        chain.import_block(a2.clone(), &mut state).unwrap();
+
+        let mut b_state = genesis.clone();
+        let b1 = child(
+            &root,
+            &mut b_state,
+            vec![
+                transfer(0xcc, 1, 0),
+                transfer(0xcc, 2, 1),
+                transfer(0xcc, 3, 2),
+            ],
+            "b",
+        );
+        let update = chain.import_block(b1.clone(), &mut state).unwrap();
+        assert_eq!(update.reverted.len(), 2);
+        assert_eq!(chain.latest_hash(), b1.hash);
+        assert_eq!(chain.chain.len(), 2);
+        assert_eq!(state, b_state);
+        assert_eq!(by_txs.branch_weight(&chain.tree, &a2.hash), 3);
+        assert_eq!(by_txs.branch_weight(&chain.tree, &b1.hash), 4);
+    }
+
+    #[test]
+    fn test_invalid_branch_is_dropped_without_reorg() {
+        let genesis = genesis_state();
+        let mut chain = Blockchain::new();
+        let mut state = genesis.clone();
+        let root = chain.chain[0].clone();
+
+        let mut a_state = genesis.clone();
+        let a1 = child(&root, &mut a_state, vec![transfer(0xbb, 10, 0)], "a");
+        chain.import_block(a1.clone(), &mut state).unwrap();
+
+        // B1 claims a state root it does not produce; as a side block it is
+        // not executed, so the problem only shows when B2 makes it win
+        let mut b_state = genesis.clone();
+        let mut b1 = child(&root, &mut b_state, vec![transfer(0xcc, 20, 0)], "b");
+        b1.header.state_root = crate::ZERO_HASH.into();
+        b1.hash = b1.header.hash();
+        let b2 = child(&b1, &mut b_state, vec![], "b");
+        chain.import_block(b1.clone(), &mut state).unwrap();
+        assert_eq!(
+            chain.import_block(b2.clone(), &mut state).unwrap_err(),
+            BlockError::StateRootMismatch
+        );
+
+        assert_eq!(chain.latest_hash(), a1.hash);
+        assert_eq!(state, a_state);
+        assert!(!chain.tree.contains(&b1.hash));
+        assert!(!chain.tree.contains(&b2.hash));
+    }
+
+    #[test]
+    fn test_finalized_first_never_reverts_finalized_blocks() {
+        let genesis = genesis_state();
+        let mut chain = Blockchain::with_fork_choice(FinalizedFirst(LongestChain));
+        let mut state = genesis.clone();
+        let root = chain.chain[0].clone();
+
+        let mut a_state = genesis.clone();
+        let a1 = child(&root, &mut a_state, vec![transfer(0xbb, 10, 0)], "a");
+        chain.import_block(a1.clone(), &mut state).unwrap();
+        assert!(chain.finalize(&a1.hash));
+
+        let mut b_state = genesis.clone();
+        let b1 = child(&root, &mut b_state, vec![], "b");
+        let b2 = child(&b1, &mut b_state, vec![], "b");
+        chain.import_block(b1, &mut state).unwrap();
+        let update = chain.import_block(b2, &mut state).unwrap();
+        assert!(update.applied.is_empty());
+        assert_eq!(chain.latest_hash(), a1.hash);
+        assert_eq!(state, a_state);
+
+        // Blocks that are not canonical cannot be finalized
+        assert!(!chain.finalize(&"f".repeat(64)));
+    }
+
+    #[test]
+    fn test_rejects_unknown_parent() {
+        let genesis = genesis_state();
+        let mut chain = Blockchain::new();
+        let mut state = genesis.clone();
+        let mut scratch = genesis.clone();
+        let orphan_parent = child(&chain.chain[0], &mut scratch, vec![], "x");
+        let orphan = child(&orphan_parent, &mut scratch, vec![], "x");
+        assert_eq!(
+            chain.import_block(orphan, &mut state).unwrap_err(),
+            BlockError::UnknownParent {
+                hash: orphan_parent.hash
+            }
+        );
+        assert_eq!(chain.tree.len(), 1);
+    }
+
+    #[test]
+    fn test_prunes_side_branches_far_below_the_tip() {
+        let genesis = genesis_state();
+        let mut chain = Blockchain::new();
+        let mut state = genesis.clone();
+        let root = chain.chain[0].clone();
+
+        let mut side_state = genesis.clone();
+        let side = child(&root, &mut side_state, vec![], "b");
+        let mut a_state = genesis.clone();
+        let mut tip = child(&root, &mut a_state, vec![], "a");
+        chain.import_block(tip.clone(), &mut state).unwrap();
+        chain.import_block(side.clone(), &mut state).unwrap();
+        while tip.header.index < MAX_FORK_DEPTH {
+            tip = child(&tip, &mut a_state, vec![], "a");
+            chain.import_block(tip.clone(), &mut state).unwrap();
+        }
+        // Forking from genesis is still within reach
+        chain.prune();
+        assert!(chain.tree.contains(&side.hash));
+
+        tip = child(&tip, &mut a_state, vec![], "a");
+        chain.import_block(tip.clone(), &mut state).unwrap();
+        chain.prune();
+        assert!(!chain.tree.contains(&side.hash));
+        assert_eq!(chain.tree.len(), chain.chain.len());
+        let late = child(&root, &mut genesis.clone(), vec![], "c");
+        assert_eq!(
+            chain.import_block(late, &mut state).unwrap_err(),
+            BlockError::ForkTooDeep {
+                fork: 0,
+                tip: MAX_FORK_DEPTH + 1
+            }
+        );
+        // Canonical blocks are still served, and the chain extends as usual
+        assert!(chain.is_valid_chain());
+        let next = child(&tip, &mut a_state, vec![], "a");
+        assert_eq!(
+            chain.import_block(next, &mut state).unwrap().applied.len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,79 @@
+// clock.rs
+// Where "now" comes from. Anything that compares block timestamps with the
+// current time takes a Clock, so tests can fix or step time instead of
+// reading the system clock.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    // Unix milliseconds
+    fn now_millis(&self) -> u64;
+
+    // Unix seconds, the resolution of block timestamps
+    fn now(&self) -> u64 {
+        self.now_millis() / 1000
+    }
+
+    // Returns once the clock reads at least `at_millis`. Blocks the calling
+    // thread, so async code calls it from a thread of its own.
+    fn sleep_until(&self, at_millis: u64);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    fn sleep_until(&self, at_millis: u64) {
+        let wait = at_millis.saturating_sub(self.now_millis());
+        thread::sleep(Duration::from_millis(wait));
+    }
+}
+
+// Only moves when told to. Holds milliseconds; the plain setters take
+// seconds.
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock(AtomicU64::new(now.saturating_mul(1000)))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.set_millis(now.saturating_mul(1000));
+    }
+
+    pub fn set_millis(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.advance_millis(secs.saturating_mul(1000));
+    }
+
+    pub fn advance_millis(&self, millis: u64) {
+        self.0.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // Nothing else moves the clock while its reader waits, so waiting
+    // skips straight to the deadline
+    fn sleep_until(&self, at_millis: u64) {
+        self.0.fetch_max(at_millis, Ordering::SeqCst);
+    }
+}
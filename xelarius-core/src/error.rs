@@ -0,0 +1,332 @@
+// error.rs
+// Reasons a transaction, block, import, stored chain, chain spec or sync
+// answer is rejected.
+// Transaction and block errors serialize with a `reason` tag so RPC clients
+// get a machine-readable cause next to the message.
+
+use crate::Address;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum TxError {
+    WrongChain { expected: String, got: String },
+    MissingSignature,
+    BadSignature,
+    NonceTooLow { expected: u64, got: u64 },
+    NonceTooHigh { expected: u64, got: u64 },
+    InsufficientBalance { needed: u64, available: u64 },
+    Overflow,
+    FeeTooLow { minimum: u64, got: u64 },
+    // Larger than a whole block, so it could never be included
+    TooLarge { maximum: u64, got: u64 },
+    // Already waiting in the mempool
+    AlreadyKnown,
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::WrongChain { expected, got } => {
+                write!(
+                    f,
+                    "transaction is for chain `{}`, expected `{}`",
+                    got, expected
+                )
+            }
+            TxError::MissingSignature => write!(f, "transaction is not signed"),
+            TxError::BadSignature => write!(f, "signature does not match the sender"),
+            TxError::NonceTooLow { expected, got } => {
+                write!(f, "nonce {} was already used, expected {}", got, expected)
+            }
+            TxError::NonceTooHigh { expected, got } => {
+                write!(f, "nonce {} is ahead of the expected {}", got, expected)
+            }
+            TxError::InsufficientBalance { needed, available } => {
+                write!(
+                    f,
+                    "insufficient balance: need {}, have {}",
+                    needed, available
+                )
+            }
+            TxError::Overflow => write!(f, "balance would overflow"),
+            TxError::FeeTooLow { minimum, got } => {
+                write!(f, "fee {} is below the minimum of {}", got, minimum)
+            }
+            TxError::TooLarge { maximum, got } => write!(
+                f,
+                "transaction is {} bytes, more than a block's {}",
+                got, maximum
+            ),
+            TxError::AlreadyKnown => write!(f, "transaction is already in the mempool"),
+        }
+    }
+}
+
+impl std::error::Error for TxError {}
+
+impl TxError {
+    // The `reason` tag as a stable code. Receipts commit to this rather than
+    // the Display text, so rewording a message cannot change a block's
+    // receipts_root.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TxError::WrongChain { .. } => "wrong_chain",
+            TxError::MissingSignature => "missing_signature",
+            TxError::BadSignature => "bad_signature",
+            TxError::NonceTooLow { .. } => "nonce_too_low",
+            TxError::NonceTooHigh { .. } => "nonce_too_high",
+            TxError::InsufficientBalance { .. } => "insufficient_balance",
+            TxError::Overflow => "overflow",
+            TxError::FeeTooLow { .. } => "fee_too_low",
+            TxError::TooLarge { .. } => "too_large",
+            TxError::AlreadyKnown => "already_known",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum BlockError {
+    WrongIndex { expected: u64, got: u64 },
+    WrongPreviousHash { expected: String, got: String },
+    TxRootMismatch,
+    HashMismatch,
+    // Only block 0 commits to the chain spec
+    UnexpectedSpecHash,
+    InvalidTransaction { position: usize, error: TxError },
+    ReceiptsRootMismatch,
+    StateRootMismatch,
+    UnknownParent { hash: String },
+    TimestampTooEarly { median_time_past: u64, got: u64 },
+    TimestampTooFarAhead { latest: u64, got: u64 },
+    // Branches off the canonical chain below the point where side branches
+    // are pruned
+    ForkTooDeep { fork: u64, tip: u64 },
+    // The transactions' canonical encodings add up to more than the chain
+    // spec's max_block_bytes
+    TooLarge { maximum: u64, got: u64 },
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::WrongIndex { expected, got } => {
+                write!(
+                    f,
+                    "block index {} does not follow the tip, expected {}",
+                    got, expected
+                )
+            }
+            BlockError::WrongPreviousHash { expected, got } => {
+                write!(f, "block builds on {}, expected {}", got, expected)
+            }
+            BlockError::TxRootMismatch => write!(f, "tx_root does not match the transactions"),
+            BlockError::HashMismatch => write!(f, "block hash does not match the header"),
+            BlockError::UnexpectedSpecHash => write!(f, "only the genesis block has a spec_hash"),
+            BlockError::InvalidTransaction { position, error } => {
+                write!(f, "transaction {} cannot be applied: {}", position, error)
+            }
+            BlockError::ReceiptsRootMismatch => {
+                write!(f, "receipts_root does not match executing the block")
+            }
+            BlockError::StateRootMismatch => {
+                write!(f, "state_root does not match executing the block")
+            }
+            BlockError::UnknownParent { hash } => write!(f, "parent block {} is unknown", hash),
+            BlockError::TimestampTooEarly {
+                median_time_past,
+                got,
+            } => write!(
+                f,
+                "timestamp {} is not after the median time past {}",
+                got, median_time_past
+            ),
+            BlockError::TimestampTooFarAhead { latest, got } => write!(
+                f,
+                "timestamp {} is ahead of the latest acceptable time {}",
+                got, latest
+            ),
+            BlockError::ForkTooDeep { fork, tip } => write!(
+                f,
+                "block forks from height {}, too far below the tip at {}",
+                fork, tip
+            ),
+            BlockError::TooLarge { maximum, got } => write!(
+                f,
+                "block carries {} bytes of transactions, more than the maximum of {}",
+                got, maximum
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+// Reasons a produced or received block does not make it onto the chain
+#[derive(Debug)]
+pub enum ImportError {
+    Invalid(BlockError),
+    // Valid, but could not be written to the database, so not imported
+    Store(LoadError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Invalid(e) => write!(f, "{}", e),
+            ImportError::Store(e) => write!(f, "cannot store block: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+// Reasons the stored chain cannot be resumed
+#[derive(Debug)]
+pub enum LoadError {
+    Storage(sled::Error),
+    MissingBlock(u64),
+    GenesisMismatch { expected: String, got: String },
+    InvalidBlock { index: u64, error: BlockError },
+    StateMismatch,
+    // The stored state is not the one the stored head committed to
+    StateRootMismatch { index: u64 },
+    MissingUndo(u64),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Storage(e) => write!(f, "storage error: {}", e),
+            LoadError::MissingBlock(index) => {
+                write!(f, "block {} is missing or unreadable", index)
+            }
+            LoadError::GenesisMismatch { expected, got } => write!(
+                f,
+                "stored genesis {} does not match this network's genesis {}",
+                got, expected
+            ),
+            LoadError::InvalidBlock { index, error } => {
+                write!(f, "stored block {} is invalid: {}", index, error)
+            }
+            LoadError::StateMismatch => {
+                write!(f, "stored state does not match re-executing the chain")
+            }
+            LoadError::StateRootMismatch { index } => write!(
+                f,
+                "stored state does not match the state root of block {}",
+                index
+            ),
+            LoadError::MissingUndo(index) => {
+                write!(f, "undo log for block {} is missing or unreadable", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<sled::Error> for LoadError {
+    fn from(e: sled::Error) -> Self {
+        LoadError::Storage(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum GenesisError {
+    Read(std::io::Error),
+    Parse(serde_json::Error),
+    EmptyChainId,
+    ZeroBlockTime,
+    // A full block would not fit in a gossip message
+    BlockTooLarge { max: u64 },
+    DuplicateAllocation(Address),
+    DuplicateValidator(Address),
+    SupplyExceeded,
+}
+
+impl fmt::Display for GenesisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenesisError::Read(e) => write!(f, "cannot read chain spec: {}", e),
+            GenesisError::Parse(e) => write!(f, "invalid chain spec: {}", e),
+            GenesisError::EmptyChainId => write!(f, "chain spec has an empty chain_id"),
+            GenesisError::ZeroBlockTime => write!(f, "block_time_secs must be positive"),
+            GenesisError::BlockTooLarge { max } => {
+                write!(f, "max_block_bytes must be at most {}", max)
+            }
+            GenesisError::DuplicateAllocation(address) => {
+                write!(f, "{} is allocated more than once", address)
+            }
+            GenesisError::DuplicateValidator(address) => {
+                write!(f, "{} is listed as a validator more than once", address)
+            }
+            GenesisError::SupplyExceeded => {
+                write!(f, "allocations exceed the maximum supply")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GenesisError {}
+
+// Why a simulation could not be set up
+#[derive(Debug)]
+pub enum SimError {
+    Genesis(GenesisError),
+    // A node's temporary database could not be opened or written
+    Storage(sled::Error),
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::Genesis(e) => write!(f, "{}", e),
+            SimError::Storage(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+impl From<GenesisError> for SimError {
+    fn from(e: GenesisError) -> Self {
+        SimError::Genesis(e)
+    }
+}
+
+impl From<sled::Error> for SimError {
+    fn from(e: sled::Error) -> Self {
+        SimError::Storage(e)
+    }
+}
+
+// Reasons a peer's answer to a sync request is turned away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncError {
+    WrongGenesis { expected: String, got: String },
+    // Not what the peer was asked for
+    Unexpected,
+    // Out of order, unlinked, longer than asked or not matching its hashes
+    Malformed,
+    NoCommonAncestor,
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::WrongGenesis { expected, got } => {
+                write!(f, "peer has genesis {}, expected {}", got, expected)
+            }
+            SyncError::Unexpected => write!(f, "answer does not match the request"),
+            SyncError::Malformed => write!(f, "answer is not a linked range of blocks"),
+            SyncError::NoCommonAncestor => {
+                write!(f, "peer's chain shares no block with ours")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
@@ -0,0 +1,266 @@
+// genesis.rs
+// Network-wide configuration fixed at genesis. A chain spec is a JSON file
+// holding the chain ID, genesis time, initial allocations, validator set and
+// protocol parameters; block 0 and the initial StateStore are built from it,
+// so every node loading the same spec agrees on the genesis hash.
+
+use crate::encoding::{Encoder, TAG_GENESIS, sha256_hex};
+use crate::error::GenesisError;
+use crate::message::{BLOCK_ENVELOPE_BYTES, MAX_MESSAGE_BYTES};
+use crate::{Address, Block, StateStore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+pub const DEVNET_CHAIN_ID: &str = "xelarius-devnet";
+pub const TESTNET_CHAIN_ID: &str = "xelarius-testnet";
+pub const MAINNET_CHAIN_ID: &str = "xelarius-mainnet";
+
+// Amounts are u64 counts of the smallest unit; 1 XZN = 10^8 units
+pub const XZN_DECIMALS: u32 = 8;
+pub const COIN: u64 = 10u64.pow(XZN_DECIMALS);
+// Fixed supply: no code path mints beyond the genesis allocation. Burned fees
+// still count towards it.
+pub const MAX_SUPPLY: u64 = 42_000_000 * COIN;
+
+// Devnet accounts come from the well-known all-"abandon" seed phrase (see
+// hd.rs), so anyone can sign for them. Never fund these anywhere else.
+pub const DEVNET_FAUCET: &str = "xzn13nkxedva2759zmu6dx54rxxv6v4w8tqwdxcnd78xyadkzyd6z5jq3c8uwa";
+pub const DEVNET_VALIDATOR: &str = "xzn1aj90u4tjrqjzyaayxv73duvmhatsyn77ka3ejs422wau705vv5eqh7ftp5";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Allocation {
+    pub address: Address,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Validator {
+    pub name: String,
+    pub address: Address,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolParams {
+    // Target seconds between blocks
+    pub block_time_secs: u64,
+    // Cap on the summed canonical size of a block's transactions
+    pub max_block_bytes: u64,
+    // Lowest fee the mempool admits
+    pub min_fee: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisConfig {
+    // Signed into every transaction so it is only valid on this network
+    pub chain_id: String,
+    // Unix seconds; the timestamp of block 0
+    pub genesis_time: u64,
+    pub allocations: Vec<Allocation>,
+    pub validators: Vec<Validator>,
+    pub params: ProtocolParams,
+}
+
+impl GenesisConfig {
+    pub fn devnet() -> Self {
+        GenesisConfig {
+            chain_id: DEVNET_CHAIN_ID.to_string(),
+            genesis_time: 0,
+            allocations: vec![Allocation {
+                address: DEVNET_FAUCET.parse().unwrap(),
+                amount: MAX_SUPPLY,
+            }],
+            validators: vec![Validator {
+                name: "devnet-0".to_string(),
+                address: DEVNET_VALIDATOR.parse().unwrap(),
+            }],
+            params: ProtocolParams {
+                block_time_secs: 5,
+                max_block_bytes: 1 << 20,
+                min_fee: 0,
+            },
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, GenesisError> {
+        let config: GenesisConfig = serde_json::from_str(json).map_err(GenesisError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, GenesisError> {
+        let json = std::fs::read_to_string(path).map_err(GenesisError::Read)?;
+        Self::from_json(&json)
+    }
+
+    pub fn validate(&self) -> Result<(), GenesisError> {
+        if self.chain_id.is_empty() {
+            return Err(GenesisError::EmptyChainId);
+        }
+        if self.params.block_time_secs == 0 {
+            return Err(GenesisError::ZeroBlockTime);
+        }
+        let max = (MAX_MESSAGE_BYTES - BLOCK_ENVELOPE_BYTES) as u64;
+        if self.params.max_block_bytes > max {
+            return Err(GenesisError::BlockTooLarge { max });
+        }
+        let mut funded = HashSet::new();
+        for allocation in &self.allocations {
+            if !funded.insert(allocation.address) {
+                return Err(GenesisError::DuplicateAllocation(allocation.address));
+            }
+        }
+        let mut validators = HashSet::new();
+        for validator in &self.validators {
+            if !validators.insert(validator.address) {
+                return Err(GenesisError::DuplicateValidator(validator.address));
+            }
+        }
+        Ok(())
+    }
+
+    // State before any block: the allocations, capped at MAX_SUPPLY
+    pub fn initial_state(&self) -> Result<StateStore, GenesisError> {
+        self.validate()?;
+        let mut state = StateStore::new(&self.chain_id);
+        for allocation in &self.allocations {
+            state
+                .allocate(allocation.address, allocation.amount)
+                .map_err(|_| GenesisError::SupplyExceeded)?;
+        }
+        Ok(state)
+    }
+
+    // Hash of the parts of the spec that do not show up in the initial
+    // state: the chain ID, the validator set and the protocol parameters
+    pub fn spec_hash(&self) -> String {
+        let params = &self.params;
+        sha256_hex(
+            &Encoder::new(TAG_GENESIS)
+                .str(&self.chain_id)
+                .list(&self.validators, |enc, validator| {
+                    enc.str(&validator.name).bytes(&validator.address.0);
+                })
+                .u64(params.block_time_secs)
+                .u64(params.max_block_bytes)
+                .u64(params.min_fee)
+                .finish(),
+        )
+    }
+
+    // Block 0 commits to the initial state through its state root and to
+    // the rest of the spec through its spec_hash, so two networks with the
+    // same allocations but another ID, validator set or parameters still get
+    // different genesis hashes.
+    pub fn build(&self) -> Result<(Block, StateStore), GenesisError> {
+        let state = self.initial_state()?;
+        let block = Block::genesis(self.genesis_time, state.state_root(), self.spec_hash());
+        Ok((block, state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GenesisError;
+
+    #[test]
+    fn test_bundled_devnet_spec_matches_code() {
+        let bundled = include_str!("../../chain-specs/devnet.json");
+        assert_eq!(
+            GenesisConfig::from_json(bundled).unwrap(),
+            GenesisConfig::devnet()
+        );
+    }
+
+    #[test]
+    fn test_genesis_commits_to_spec() {
+        let spec = GenesisConfig::devnet();
+        let (block, state) = spec.build().unwrap();
+        assert_eq!(state.total_supply(), Some(MAX_SUPPLY));
+        assert_eq!(state.balance(&DEVNET_FAUCET.parse().unwrap()), MAX_SUPPLY);
+        assert_eq!(block.header.index, 0);
+        assert_eq!(block.header.state_root, state.state_root());
+        assert_eq!(block.header.spec_hash, Some(spec.spec_hash()));
+        assert!(block.header.proposer.is_empty());
+        assert_eq!(block.hash, block.header.hash());
+        // Known answers; changing them forks every existing devnet database
+        assert_eq!(
+            block.header.state_root,
+            "2706d6f498c75314ba725aa1372ecffa5657c76d1d456789d0ea5d88524c18eb"
+        );
+        assert_eq!(
+            block.hash,
+            "d41ba5ca4b679524c8a0d7a7c34f00f330185faa025dc62071eb9087d28d0012"
+        );
+
+        let mut other = spec.clone();
+        other.chain_id = TESTNET_CHAIN_ID.into();
+        assert_ne!(other.build().unwrap().0.hash, block.hash);
+        let mut other = spec.clone();
+        other.genesis_time = 1;
+        assert_ne!(other.build().unwrap().0.hash, block.hash);
+        let mut other = spec.clone();
+        other.validators[0].name = "devnet-1".into();
+        assert_ne!(other.build().unwrap().0.hash, block.hash);
+        let mut other = spec.clone();
+        other.params.min_fee = 1;
+        assert_ne!(other.build().unwrap().0.hash, block.hash);
+        let mut other = spec;
+        other.allocations[0].amount -= 1;
+        assert_ne!(
+            other.build().unwrap().0.header.state_root,
+            block.header.state_root
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_specs() {
+        let spec = GenesisConfig::devnet();
+        let json = serde_json::to_string(&spec).unwrap();
+        assert!(matches!(
+            GenesisConfig::from_json(&json.replace("\"min_fee\"", "\"min_fees\"")),
+            Err(GenesisError::Parse(_))
+        ));
+        // Addresses must be valid bech32m
+        assert!(matches!(
+            GenesisConfig::from_json(&json.replace(DEVNET_FAUCET, "xzn1faucet")),
+            Err(GenesisError::Parse(_))
+        ));
+
+        let mut bad = spec.clone();
+        bad.chain_id.clear();
+        assert!(matches!(bad.build(), Err(GenesisError::EmptyChainId)));
+        let mut bad = spec.clone();
+        bad.params.block_time_secs = 0;
+        assert!(matches!(bad.build(), Err(GenesisError::ZeroBlockTime)));
+        let mut bad = spec.clone();
+        bad.params.max_block_bytes = MAX_MESSAGE_BYTES as u64;
+        assert!(matches!(
+            bad.build(),
+            Err(GenesisError::BlockTooLarge { .. })
+        ));
+        let mut bad = spec.clone();
+        bad.allocations.push(bad.allocations[0].clone());
+        assert!(matches!(
+            bad.build(),
+            Err(GenesisError::DuplicateAllocation(_))
+        ));
+        let mut bad = spec.clone();
+        bad.validators.push(bad.validators[0].clone());
+        assert!(matches!(
+            bad.build(),
+            Err(GenesisError::DuplicateValidator(_))
+        ));
+        let mut bad = spec;
+        bad.allocations.push(Allocation {
+            address: Address([0xcc; 32]),
+            amount: 1,
+        });
+        assert!(matches!(bad.build(), Err(GenesisError::SupplyExceeded)));
+    }
+}
@@ -0,0 +1,273 @@
+// hd.rs
+// Mnemonic seed phrases (BIP39) and hierarchical deterministic derivation of
+// ed25519 keys (SLIP-0010), so one backed-up phrase recovers every account.
+//
+// Accounts live at m/44'/4242'/<account>'/0'/<index>'. SLIP-0010 only defines
+// hardened derivation for ed25519, so every path component must be hardened.
+
+use crate::Wallet;
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+pub const XZN_COIN_TYPE: u32 = 4242;
+pub const HARDENED: u32 = 0x8000_0000;
+
+const SEED_KEY: &[u8] = b"ed25519 seed";
+
+#[derive(Debug)]
+pub enum HdError {
+    Mnemonic(bip39::Error),
+    InvalidPath(String),
+}
+
+impl fmt::Display for HdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HdError::Mnemonic(e) => write!(f, "invalid mnemonic: {}", e),
+            HdError::InvalidPath(e) => write!(f, "invalid derivation path: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HdError {}
+
+impl From<bip39::Error> for HdError {
+    fn from(e: bip39::Error) -> Self {
+        HdError::Mnemonic(e)
+    }
+}
+
+// Child indices, all with the hardened bit set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivationPath(Vec<u32>);
+
+impl DerivationPath {
+    // Both numbers must be below 2^31; the hardened bit is added here
+    pub fn account(account: u32, index: u32) -> Result<Self, HdError> {
+        for (name, value) in [("account", account), ("index", index)] {
+            if value >= HARDENED {
+                return Err(HdError::InvalidPath(format!(
+                    "{} {} is not below 2^31",
+                    name, value
+                )));
+            }
+        }
+        Ok(DerivationPath(vec![
+            44 | HARDENED,
+            XZN_COIN_TYPE | HARDENED,
+            account | HARDENED,
+            HARDENED,
+            index | HARDENED,
+        ]))
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.0
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = HdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err(HdError::InvalidPath(format!("`{}` must start with `m`", s)));
+        }
+        parts
+            .map(|part| {
+                let digits = part
+                    .strip_suffix('\'')
+                    .or_else(|| part.strip_suffix('h'))
+                    .or_else(|| part.strip_suffix('H'))
+                    .ok_or_else(|| {
+                        HdError::InvalidPath(format!("component `{}` is not hardened", part))
+                    })?;
+                match digits.parse::<u32>() {
+                    Ok(index) if index < HARDENED => Ok(index | HARDENED),
+                    _ => Err(HdError::InvalidPath(format!(
+                        "component `{}` is not a valid index",
+                        part
+                    ))),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(DerivationPath)
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("m")?;
+        for index in &self.0 {
+            write!(f, "/{}'", index & !HARDENED)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn generate_mnemonic(word_count: usize) -> Result<Mnemonic, HdError> {
+    Ok(Mnemonic::generate(word_count)?)
+}
+
+fn hmac_sha512(key: &[u8], parts: &[&[u8]]) -> Zeroizing<[u8; 64]> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    Zeroizing::new(mac.finalize().into_bytes().into())
+}
+
+// Returns (secret key, chain code) for `path` under `seed`
+pub fn derive_key(seed: &[u8], path: &DerivationPath) -> (Zeroizing<[u8; 32]>, [u8; 32]) {
+    let mut node = hmac_sha512(SEED_KEY, &[seed]);
+    for index in path.indices() {
+        let (key, chain_code) = node.split_at(32);
+        node = hmac_sha512(chain_code, &[&[0], key, &index.to_be_bytes()]);
+    }
+    let mut secret = Zeroizing::new([0u8; 32]);
+    let mut chain_code = [0u8; 32];
+    secret.copy_from_slice(&node[..32]);
+    chain_code.copy_from_slice(&node[32..]);
+    (secret, chain_code)
+}
+
+impl Wallet {
+    pub fn from_seed(seed: &[u8], path: &DerivationPath) -> Self {
+        let (secret, _) = derive_key(seed, path);
+        Wallet::from_secret_key(&secret)
+    }
+
+    pub fn from_mnemonic(
+        phrase: &str,
+        passphrase: &str,
+        path: &DerivationPath,
+    ) -> Result<Self, HdError> {
+        let mnemonic = Mnemonic::parse(phrase)?;
+        let seed = Zeroizing::new(mnemonic.to_seed(passphrase));
+        Ok(Wallet::from_seed(seed.as_ref(), path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABANDON: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    // SLIP-0010 test vector 1 for ed25519
+    #[test]
+    fn test_slip10_vectors() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let cases = [
+            (
+                "m",
+                "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+                "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb",
+            ),
+            (
+                "m/0'",
+                "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+                "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69",
+            ),
+            (
+                "m/0'/1'",
+                "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2",
+                "a320425f77d1b5c2505a6b1b27382b37368ee640e3557c315416801243552f14",
+            ),
+            (
+                "m/0'/1'/2'/2'/1000000000'",
+                "8f94d394a8e8fd6b1bc2f3f49f5c47e385281d5c17e65324b0f62483e37e8793",
+                "68789923a0cac2cd5a29172a475fe9e0fb14cd6adb5ad98a3fa70333e7afa230",
+            ),
+        ];
+        for (path, secret, chain_code) in cases {
+            let (k, c) = derive_key(&seed, &path.parse().unwrap());
+            assert_eq!(hex::encode(*k), secret, "{path}");
+            assert_eq!(hex::encode(c), chain_code, "{path}");
+        }
+    }
+
+    // Addresses derived from the well-known all-"abandon" phrase; these must
+    // never change between releases
+    #[test]
+    fn test_mnemonic_address_vectors() {
+        let cases = [
+            (
+                0,
+                0,
+                "xzn13nkxedva2759zmu6dx54rxxv6v4w8tqwdxcnd78xyadkzyd6z5jq3c8uwa",
+            ),
+            (
+                0,
+                1,
+                "xzn1aj90u4tjrqjzyaayxv73duvmhatsyn77ka3ejs422wau705vv5eqh7ftp5",
+            ),
+            (
+                1,
+                0,
+                "xzn17xfvrlf78h7cmgdy5nntn35my257qwvn9xru9k8g0wx3yverqggs43gzts",
+            ),
+        ];
+        for (account, index, address) in cases {
+            let wallet = Wallet::from_mnemonic(
+                ABANDON,
+                "",
+                &DerivationPath::account(account, index).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(wallet.address.to_string(), address);
+        }
+        let with_passphrase =
+            Wallet::from_mnemonic(ABANDON, "TREZOR", &DerivationPath::account(0, 0).unwrap())
+                .unwrap();
+        assert_eq!(
+            with_passphrase.address.to_string(),
+            "xzn1mwznp6m48hnf0uqmvvtaupmf4ewdetd9nyq0z2eguasldfpzjx8s47s70c"
+        );
+    }
+
+    #[test]
+    fn test_bip39_seed_vector() {
+        let mnemonic = Mnemonic::parse(ABANDON).unwrap();
+        assert_eq!(
+            hex::encode(mnemonic.to_seed("TREZOR")),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+    }
+
+    #[test]
+    fn test_generated_phrase_recovers_same_accounts() {
+        let mnemonic = generate_mnemonic(24).unwrap();
+        assert_eq!(mnemonic.word_count(), 24);
+        let phrase = mnemonic.to_string();
+        let path = DerivationPath::account(3, 7).unwrap();
+        let a = Wallet::from_mnemonic(&phrase, "pw", &path).unwrap();
+        let b = Wallet::from_mnemonic(&phrase, "pw", &path).unwrap();
+        assert_eq!(a.address, b.address);
+        let other =
+            Wallet::from_mnemonic(&phrase, "pw", &DerivationPath::account(3, 8).unwrap()).unwrap();
+        assert_ne!(a.address, other.address);
+    }
+
+    #[test]
+    fn test_rejects_bad_phrases_and_paths() {
+        let typo = ABANDON.replace("about", "abound");
+        assert!(matches!(
+            Wallet::from_mnemonic(&typo, "", &DerivationPath::account(0, 0).unwrap()),
+            Err(HdError::Mnemonic(_))
+        ));
+        assert!("m/44'/0".parse::<DerivationPath>().is_err());
+        assert!("44'/0'".parse::<DerivationPath>().is_err());
+        assert!("m/2147483648'".parse::<DerivationPath>().is_err());
+        assert!(DerivationPath::account(HARDENED, 0).is_err());
+        assert!(DerivationPath::account(0, HARDENED | 5).is_err());
+        let path = DerivationPath::account(2, 5).unwrap();
+        assert_eq!(path.to_string(), "m/44'/4242'/2'/0'/5'");
+        assert_eq!(path.to_string().parse::<DerivationPath>().unwrap(), path);
+    }
+}
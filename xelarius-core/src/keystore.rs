@@ -0,0 +1,415 @@
+// keystore.rs
+// Passphrase-encrypted key files for wallets.
+//
+// Each key lives in `<dir>/<name>.json`. The secret key is encrypted with
+// XChaCha20-Poly1305 under a key derived from the passphrase with Argon2id.
+// The address is bound in as associated data, so a file whose address was
+// edited fails to decrypt. Plaintext secrets and derived keys only ever live
+// in zeroize-on-drop buffers.
+
+use crate::{Address, Wallet};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use zeroize::Zeroizing;
+
+const KEYSTORE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    // OWASP recommended Argon2id settings: 19 MiB, 2 passes, 1 lane
+    fn default() -> Self {
+        KdfParams {
+            m_cost: 19 * 1024,
+            t_cost: 2,
+            p_cost: 1,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    address: Address,
+    kdf: KdfParams,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    InvalidName(String),
+    NotFound(String),
+    AlreadyExists(String),
+    // Wrong passphrase, or a file that was tampered with
+    Decryption,
+    Corrupt(String),
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "keystore I/O error: {}", e),
+            KeystoreError::InvalidName(name) => write!(f, "invalid key name `{}`", name),
+            KeystoreError::NotFound(name) => write!(f, "no key named `{}`", name),
+            KeystoreError::AlreadyExists(name) => {
+                write!(f, "a key named `{}` already exists", name)
+            }
+            KeystoreError::Decryption => write!(f, "wrong passphrase or corrupted key file"),
+            KeystoreError::Corrupt(e) => write!(f, "corrupt key file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeystoreEntry {
+    pub name: String,
+    pub address: Address,
+}
+
+pub struct Keystore {
+    dir: PathBuf,
+    kdf: KdfParams,
+}
+
+impl Keystore {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, KeystoreError> {
+        Keystore::with_params(dir, KdfParams::default())
+    }
+
+    // Parameters used for newly written keys; existing files keep their own
+    pub fn with_params(dir: impl AsRef<Path>, kdf: KdfParams) -> Result<Self, KeystoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Keystore { dir, kdf })
+    }
+
+    // Files that cannot be read or parsed are logged and left out, so one bad
+    // file does not hide the other keys
+    pub fn list(&self) -> Result<Vec<KeystoreEntry>, KeystoreError> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let file = match self.read(name) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Skipping key file {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            entries.push(KeystoreEntry {
+                name: name.to_string(),
+                address: file.address,
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    pub fn save(&self, name: &str, wallet: &Wallet, passphrase: &str) -> Result<(), KeystoreError> {
+        let path = self.path(name)?;
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 24];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+
+        let key = derive_key(passphrase, &salt, self.kdf)?;
+        let secret = wallet.secret_key();
+        let ciphertext = XChaCha20Poly1305::new(key.as_ref().into())
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: secret.as_ref(),
+                    aad: &wallet.address.0,
+                },
+            )
+            .expect("encrypting a 32-byte secret cannot fail");
+
+        let file = KeyFile {
+            version: KEYSTORE_VERSION,
+            address: wallet.address,
+            kdf: self.kdf,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        };
+        let json =
+            serde_json::to_vec_pretty(&file).map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+        match write_private(&path, &json) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(KeystoreError::AlreadyExists(name.to_string()))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    pub fn load(&self, name: &str, passphrase: &str) -> Result<Wallet, KeystoreError> {
+        let file = self.read(name)?;
+        let salt = hex::decode(&file.salt).map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+        let nonce = hex::decode(&file.nonce).map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+        let ciphertext =
+            hex::decode(&file.ciphertext).map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+        if nonce.len() != 24 {
+            return Err(KeystoreError::Corrupt("nonce must be 24 bytes".into()));
+        }
+
+        let key = derive_key(passphrase, &salt, file.kdf)?;
+        let plaintext = Zeroizing::new(
+            XChaCha20Poly1305::new(key.as_ref().into())
+                .decrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: &ciphertext,
+                        aad: &file.address.0,
+                    },
+                )
+                .map_err(|_| KeystoreError::Decryption)?,
+        );
+        let secret: Zeroizing<[u8; 32]> = Zeroizing::new(
+            plaintext
+                .as_slice()
+                .try_into()
+                .map_err(|_| KeystoreError::Corrupt("secret key must be 32 bytes".into()))?,
+        );
+        let wallet = Wallet::from_secret_key(&secret);
+        if wallet.address != file.address {
+            return Err(KeystoreError::Corrupt("address does not match key".into()));
+        }
+        Ok(wallet)
+    }
+
+    pub fn import(
+        &self,
+        name: &str,
+        secret_key: &[u8; 32],
+        passphrase: &str,
+    ) -> Result<Address, KeystoreError> {
+        let wallet = Wallet::from_secret_key(secret_key);
+        self.save(name, &wallet, passphrase)?;
+        Ok(wallet.address)
+    }
+
+    // Returns the raw secret key; callers must not keep it around
+    pub fn export(
+        &self,
+        name: &str,
+        passphrase: &str,
+    ) -> Result<Zeroizing<[u8; 32]>, KeystoreError> {
+        Ok(self.load(name, passphrase)?.secret_key())
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), KeystoreError> {
+        let path = self.path(name)?;
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(KeystoreError::NotFound(name.to_string()))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<KeyFile, KeystoreError> {
+        let path = self.path(name)?;
+        let bytes = match fs::read(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(KeystoreError::NotFound(name.to_string()));
+            }
+            result => result?,
+        };
+        let file: KeyFile =
+            serde_json::from_slice(&bytes).map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+        if file.version != KEYSTORE_VERSION {
+            return Err(KeystoreError::Corrupt(format!(
+                "unsupported keystore version {}",
+                file.version
+            )));
+        }
+        Ok(file)
+    }
+
+    // Names become file names, so keep them to a safe character set
+    fn path(&self, name: &str) -> Result<PathBuf, KeystoreError> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            && !name.starts_with('.');
+        if !valid {
+            return Err(KeystoreError::InvalidName(name.to_string()));
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    kdf: KdfParams,
+) -> Result<Zeroizing<[u8; 32]>, KeystoreError> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+    Ok(key)
+}
+
+// Writes a temporary file and hard-links it into place, so a crash never
+// leaves a half-written key and an existing key is never replaced, even by a
+// concurrent save. Readable only by the owner on unix.
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut suffix = [0u8; 8];
+    rand::rngs::OsRng.fill_bytes(&mut suffix);
+    let tmp = path.with_extension(format!("json.{}.tmp", hex::encode(suffix)));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options.open(&tmp).and_then(|mut file| {
+        use std::io::Write;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::hard_link(&tmp, path)
+    });
+    let _ = fs::remove_file(&tmp);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap parameters so the tests stay fast
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn keystore() -> (tempfile::TempDir, Keystore) {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = Keystore::with_params(dir.path(), TEST_KDF).unwrap();
+        (dir, keystore)
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let (_dir, keystore) = keystore();
+        let wallet = Wallet::generate();
+        keystore.save("validator", &wallet, "hunter2").unwrap();
+
+        let loaded = keystore.load("validator", "hunter2").unwrap();
+        assert_eq!(loaded.address, wallet.address);
+        assert_eq!(*loaded.secret_key(), *wallet.secret_key());
+
+        assert!(matches!(
+            keystore.load("validator", "wrong"),
+            Err(KeystoreError::Decryption)
+        ));
+        assert!(matches!(
+            keystore.save("validator", &wallet, "hunter2"),
+            Err(KeystoreError::AlreadyExists(_))
+        ));
+        // The refused save leaves only the original file behind
+        assert_eq!(fs::read_dir(&keystore.dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_file_holds_no_plaintext_secret() {
+        let (dir, keystore) = keystore();
+        let wallet = Wallet::generate();
+        keystore.save("treasury", &wallet, "pw").unwrap();
+        let contents = fs::read_to_string(dir.path().join("treasury.json")).unwrap();
+        assert!(!contents.contains(&hex::encode(*wallet.secret_key())));
+        assert!(contents.contains(&wallet.address.to_string()));
+    }
+
+    #[test]
+    fn test_tampered_address_fails_to_decrypt() {
+        let (dir, keystore) = keystore();
+        let wallet = Wallet::generate();
+        keystore.save("k", &wallet, "pw").unwrap();
+        let path = dir.path().join("k.json");
+        let contents = fs::read_to_string(&path).unwrap();
+        let other = Wallet::generate().address.to_string();
+        fs::write(&path, contents.replace(&wallet.address.to_string(), &other)).unwrap();
+        assert!(matches!(
+            keystore.load("k", "pw"),
+            Err(KeystoreError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_list_import_export_delete() {
+        let (_dir, keystore) = keystore();
+        let secret = [9u8; 32];
+        let address = keystore.import("b-key", &secret, "pw").unwrap();
+        keystore.save("a-key", &Wallet::generate(), "pw").unwrap();
+
+        let names: Vec<String> = keystore
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec!["a-key", "b-key"]);
+        assert_eq!(keystore.list().unwrap()[1].address, address);
+
+        assert_eq!(*keystore.export("b-key", "pw").unwrap(), secret);
+
+        // An unreadable file is skipped rather than failing the whole list
+        fs::write(keystore.dir.join("broken.json"), b"not json").unwrap();
+        assert_eq!(keystore.list().unwrap().len(), 2);
+
+        keystore.delete("b-key").unwrap();
+        assert!(matches!(
+            keystore.delete("b-key"),
+            Err(KeystoreError::NotFound(_))
+        ));
+        assert_eq!(keystore.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rejects_path_like_names() {
+        let (_dir, keystore) = keystore();
+        for name in ["", "../escape", "a/b", ".hidden"] {
+            assert!(matches!(
+                keystore.delete(name),
+                Err(KeystoreError::InvalidName(_))
+            ));
+        }
+    }
+}
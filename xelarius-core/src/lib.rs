@@ -1,24 +1,179 @@
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use sled::Db;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use zeroize::Zeroizing;
+
+pub mod address;
+pub mod block_tree;
+pub mod clock;
+pub mod encoding;
+pub mod error;
+pub mod genesis;
+pub mod hd;
+pub mod keystore;
+pub mod merkle;
+pub mod message;
+pub mod receipt;
+pub mod service;
+pub mod sim;
+pub mod smt;
+pub mod state;
+pub mod sync;
+
+pub use address::Address;
+pub use block_tree::{ChainUpdate, FinalizedFirst, ForkChoice, HeaviestChain, LongestChain};
+pub use ed25519_dalek::Signature;
+pub use error::{BlockError, GenesisError, ImportError, LoadError, SyncError, TxError};
+pub use state::{AccountProof, ChangeSet, StateStore, Supply, UndoLog};
+
+use block_tree::BlockTree;
+use clock::{Clock, SystemClock};
+use encoding::{Encoder, TAG_BLOCK, TAG_TX, TAG_TX_SIGNING, sha256, sha256_hex, to_hex};
+use merkle::{Hash, MerkleProof, merkle_root};
+use receipt::{IndexedReceipt, Receipt};
+use sled::Transactional;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct TxHash(pub [u8; 32]);
+
+impl std::fmt::Display for TxHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&to_hex(&self.0))
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Transaction {
-    pub from: String,
-    pub to: String,
+    pub chain_id: String,
+    pub from: Address,
+    pub to: Address,
     pub amount: u64,
+    pub fee: u64,
     pub nonce: u64,
-    pub signature: Option<String>, // For signature validation stub
+    pub signature: Option<Signature>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Block {
+impl Transaction {
+    // Bytes covered by the signature: every field except the signature itself
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        Encoder::new(TAG_TX_SIGNING)
+            .str(&self.chain_id)
+            .bytes(&self.from.0)
+            .bytes(&self.to.0)
+            .u64(self.amount)
+            .u64(self.fee)
+            .u64(self.nonce)
+            .finish()
+    }
+
+    // The sender address is the ed25519 public key that must have produced
+    // the signature over signing_bytes()
+    pub fn verify_signature(&self) -> bool {
+        let (Some(signature), Some(key)) = (&self.signature, self.from.public_key()) else {
+            return false;
+        };
+        key.verify_strict(&self.signing_bytes(), signature).is_ok()
+    }
+
+    // Checks that need no account state: the chain and the signature
+    pub fn check(&self, chain_id: &str) -> Result<(), TxError> {
+        // Replay protection: only transactions signed for this chain
+        if self.chain_id != chain_id {
+            return Err(TxError::WrongChain {
+                expected: chain_id.to_string(),
+                got: self.chain_id.clone(),
+            });
+        }
+        if self.signature.is_none() {
+            return Err(TxError::MissingSignature);
+        }
+        if !self.verify_signature() {
+            return Err(TxError::BadSignature);
+        }
+        Ok(())
+    }
+
+    // Stable identifier: SHA-256 of the full canonical encoding
+    pub fn hash(&self) -> TxHash {
+        TxHash(sha256(&self.canonical_bytes()))
+    }
+
+    // Value committed to by the block's transaction Merkle tree
+    pub fn leaf_hash(&self) -> Hash {
+        self.hash().0
+    }
+
+    // Full canonical encoding, including the signature
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        Encoder::new(TAG_TX)
+            .str(&self.chain_id)
+            .bytes(&self.from.0)
+            .bytes(&self.to.0)
+            .u64(self.amount)
+            .u64(self.fee)
+            .u64(self.nonce)
+            .option(self.signature.as_ref(), |e, sig| {
+                e.bytes(&sig.to_bytes());
+            })
+            .finish()
+    }
+}
+
+pub const ZERO_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockHeader {
     pub index: u64,
     pub timestamp: u64,
-    pub transactions: Vec<Transaction>,
     pub previous_hash: String,
+    pub tx_root: String,
+    pub receipts_root: String,
+    pub state_root: String,
+    pub proposer: String,
+    // Block 0's commitment to the chain spec (GenesisConfig::spec_hash);
+    // None in every later block
+    pub spec_hash: Option<String>,
+}
+
+impl BlockHeader {
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        Encoder::new(TAG_BLOCK)
+            .u64(self.index)
+            .u64(self.timestamp)
+            .str(&self.previous_hash)
+            .str(&self.tx_root)
+            .str(&self.receipts_root)
+            .str(&self.state_root)
+            .str(&self.proposer)
+            .option(self.spec_hash.as_deref(), |enc, hash| {
+                enc.str(hash);
+            })
+            .finish()
+    }
+
+    pub fn hash(&self) -> String {
+        sha256_hex(&self.canonical_bytes())
+    }
+
+    // Checks that `tx` is committed to by this header's tx_root, so clients
+    // can confirm inclusion from the header and a proof alone
+    pub fn verify_tx(&self, tx: &Transaction, proof: &MerkleProof) -> bool {
+        proof
+            .compute_root(&tx.leaf_hash())
+            .is_some_and(|root| to_hex(&root) == self.tx_root)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
     pub hash: String,
 }
 
@@ -27,79 +182,499 @@ impl Block {
         index: u64,
         timestamp: u64,
         transactions: Vec<Transaction>,
+        receipts: &[Receipt],
         previous_hash: String,
+        state_root: String,
+        proposer: String,
     ) -> Self {
-        let mut hasher = Sha256::new();
-        hasher.update(format!(
-            "{}{}{:?}{}",
-            index, timestamp, transactions, &previous_hash
-        ));
-        let hash = format!("{:x}", hasher.finalize());
-
-        Block {
+        let header = BlockHeader {
             index,
             timestamp,
-            transactions,
             previous_hash,
+            tx_root: Block::calculate_tx_root(&transactions),
+            receipts_root: Receipt::root(receipts),
+            state_root,
+            proposer,
+            spec_hash: None,
+        };
+        let hash = header.hash();
+
+        Block {
+            header,
+            transactions,
             hash,
         }
     }
 
+    // Block 0 of a network: no transactions or proposer, committing to the
+    // initial state and to the chain spec
+    pub fn genesis(timestamp: u64, state_root: String, spec_hash: String) -> Self {
+        let mut block = Block::new(
+            0,
+            timestamp,
+            vec![],
+            &[],
+            "0".into(),
+            state_root,
+            String::new(),
+        );
+        block.header.spec_hash = Some(spec_hash);
+        block.hash = block.header.hash();
+        block
+    }
+
+    pub fn validate(&self, prev_block: &Block) -> Result<(), BlockError> {
+        if self.header.index != prev_block.header.index + 1 {
+            return Err(BlockError::WrongIndex {
+                expected: prev_block.header.index + 1,
+                got: self.header.index,
+            });
+        }
+        if self.header.previous_hash != prev_block.hash {
+            return Err(BlockError::WrongPreviousHash {
+                expected: prev_block.hash.clone(),
+                got: self.header.previous_hash.clone(),
+            });
+        }
+        if self.header.tx_root != Block::calculate_tx_root(&self.transactions) {
+            return Err(BlockError::TxRootMismatch);
+        }
+        if self.hash != self.header.hash() {
+            return Err(BlockError::HashMismatch);
+        }
+        if self.header.spec_hash.is_some() {
+            return Err(BlockError::UnexpectedSpecHash);
+        }
+        Ok(())
+    }
+
+    // What counts against max_block_bytes: the canonical encodings of the
+    // transactions, as the mempool measures them when filling a block
+    pub fn size(&self) -> u64 {
+        self.transactions
+            .iter()
+            .map(|tx| tx.canonical_bytes().len() as u64)
+            .fold(0, u64::saturating_add)
+    }
+
+    pub fn check_size(&self, max_block_bytes: u64) -> Result<(), BlockError> {
+        let size = self.size();
+        if size > max_block_bytes {
+            return Err(BlockError::TooLarge {
+                maximum: max_block_bytes,
+                got: size,
+            });
+        }
+        Ok(())
+    }
+
     pub fn is_valid(&self, prev_block: &Block) -> bool {
-        self.index == prev_block.index + 1
-            && self.previous_hash == prev_block.hash
-            && self.hash
-                == Block::calculate_hash(
-                    self.index,
-                    self.timestamp,
-                    &self.transactions,
-                    &self.previous_hash,
-                )
-    }
-
-    pub fn calculate_hash(
-        index: u64,
-        timestamp: u64,
-        transactions: &Vec<Transaction>,
-        previous_hash: &str,
-    ) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(format!(
-            "{}{}{:?}{}",
-            index, timestamp, transactions, previous_hash
-        ));
-        format!("{:x}", hasher.finalize())
+        self.validate(prev_block).is_ok()
+    }
+
+    pub fn calculate_tx_root(transactions: &[Transaction]) -> String {
+        let leaves: Vec<Hash> = transactions.iter().map(Transaction::leaf_hash).collect();
+        to_hex(&merkle_root(&leaves))
+    }
+
+    pub fn tx_proof(&self, position: usize) -> Option<MerkleProof> {
+        let leaves: Vec<Hash> = self
+            .transactions
+            .iter()
+            .map(Transaction::leaf_hash)
+            .collect();
+        MerkleProof::new(&leaves, position)
     }
 }
 
-#[derive(Debug, Default)]
+// A block's timestamp must be later than the median of the timestamps of its
+// parent and up to MEDIAN_TIME_SPAN - 1 of the parent's ancestors. Using the
+// median rather than the parent alone tolerates one producer's clock running
+// a little fast.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// Default for how far ahead of the local clock a received block may be
+pub const MAX_FUTURE_DRIFT: u64 = 15;
+// Side branches forking more than this many blocks below the tip are
+// pruned, and new ones are refused
+pub const MAX_FORK_DEPTH: u64 = 100;
+
+// `chain` is the canonical branch, genesis first. `tree` also keeps every
+// valid side branch, and `fork_choice` decides when one of them takes over.
+// Blocks received through import_block are also checked against `clock`.
+// No block may exceed `max_block_bytes`, wherever it came from.
+// `undo` holds what each canonical block after genesis overwrote in the
+// state, so the block can be unwound again. Side branches forking from
+// canonical blocks below `pruned_below` have been removed from the tree.
+#[derive(Debug)]
 pub struct Blockchain {
     pub chain: Vec<Block>,
+    pub tree: BlockTree,
+    undo: Vec<UndoLog>,
+    pruned_below: u64,
+    fork_choice: Box<dyn ForkChoice>,
+    pub clock: Arc<dyn Clock>,
+    pub max_future_drift: u64,
+    pub max_block_bytes: u64,
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Blockchain {
     pub fn new() -> Self {
-        let genesis = Block::new(0, 0, vec![], "0".into());
+        Self::with_fork_choice(LongestChain)
+    }
+
+    // Starts from an empty genesis block; nodes use from_genesis with the
+    // block built from their chain spec
+    pub fn with_fork_choice(fork_choice: impl ForkChoice + 'static) -> Self {
+        let genesis = Block::new(
+            0,
+            0,
+            vec![],
+            &[],
+            "0".into(),
+            ZERO_HASH.into(),
+            String::new(),
+        );
+        Self::from_genesis(genesis, fork_choice)
+    }
+
+    pub fn from_genesis(genesis: Block, fork_choice: impl ForkChoice + 'static) -> Self {
         Blockchain {
+            tree: BlockTree::new(&genesis),
             chain: vec![genesis],
+            undo: Vec::new(),
+            pruned_below: 0,
+            fork_choice: Box::new(fork_choice),
+            clock: Arc::new(SystemClock),
+            max_future_drift: MAX_FUTURE_DRIFT,
+            max_block_bytes: u64::MAX,
         }
     }
 
     pub fn latest_hash(&self) -> String {
-        self.chain.last().unwrap().hash.clone()
+        self.tip().hash.clone()
+    }
+
+    pub fn tip(&self) -> &Block {
+        self.chain.last().unwrap()
+    }
+
+    // Builds the block that would extend the tip, without adding it
+    pub fn next_block(
+        &self,
+        transactions: Vec<Transaction>,
+        receipts: &[Receipt],
+        timestamp: u64,
+        state_root: String,
+        proposer: String,
+    ) -> Block {
+        Block::new(
+            self.chain.len() as u64,
+            timestamp,
+            transactions,
+            receipts,
+            self.latest_hash(),
+            state_root,
+            proposer,
+        )
+    }
+
+    // Executes `txs` against `state` without changing it and builds the block
+    // that would extend the tip, stamped from the chain's clock. Transactions
+    // that cannot be applied are left out and returned with the reason.
+    pub fn build_block(
+        &self,
+        state: &StateStore,
+        txs: Vec<Transaction>,
+        proposer: String,
+    ) -> BuiltBlock {
+        let mut overlay = state.overlay();
+        let mut included = Vec::new();
+        let mut receipts = Vec::new();
+        let mut dropped = Vec::new();
+        for tx in txs {
+            match overlay.apply_tx(&tx) {
+                Ok(receipt) => {
+                    included.push(tx);
+                    receipts.push(receipt);
+                }
+                Err(e) => dropped.push((tx, e)),
+            }
+        }
+        let state_root = overlay.state_root();
+        let timestamp = self.next_timestamp(self.clock.now());
+        BuiltBlock {
+            block: self.next_block(included, &receipts, timestamp, state_root, proposer),
+            receipts,
+            changes: overlay.into_changes(),
+            dropped,
+        }
+    }
+
+    // Median timestamp of `hash` and up to MEDIAN_TIME_SPAN - 1 ancestors;
+    // None if the block is unknown
+    pub fn median_time_past(&self, hash: &str) -> Option<u64> {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut next = self.tree.get(hash);
+        while let Some(block) = next.filter(|_| times.len() < MEDIAN_TIME_SPAN) {
+            times.push(block.header.timestamp);
+            next = self.tree.get(&block.header.previous_hash);
+        }
+        times.sort_unstable();
+        times.get(times.len() / 2).copied()
+    }
+
+    // Earliest timestamp a block on the tip may carry, for producers whose
+    // clock has not moved past the median yet
+    pub fn next_timestamp(&self, now: u64) -> u64 {
+        let median = self.median_time_past(&self.tip().hash).unwrap_or(0);
+        now.max(median + 1)
+    }
+
+    // The consensus rule: later than the parent's median time past
+    fn check_timestamp(&self, block: &Block) -> Result<(), BlockError> {
+        let median = self
+            .median_time_past(&block.header.previous_hash)
+            .ok_or_else(|| BlockError::UnknownParent {
+                hash: block.header.previous_hash.clone(),
+            })?;
+        if block.header.timestamp <= median {
+            return Err(BlockError::TimestampTooEarly {
+                median_time_past: median,
+                got: block.header.timestamp,
+            });
+        }
+        Ok(())
+    }
+
+    // Local policy for blocks as they arrive: not too far ahead of our clock.
+    // Depends on when it runs, so it is never applied to stored blocks.
+    fn check_drift(&self, block: &Block) -> Result<(), BlockError> {
+        let latest = self.clock.now().saturating_add(self.max_future_drift);
+        if block.header.timestamp > latest {
+            return Err(BlockError::TimestampTooFarAhead {
+                latest,
+                got: block.header.timestamp,
+            });
+        }
+        Ok(())
+    }
+
+    // Every block on the tip must pass these, wherever it came from
+    pub(crate) fn check_extends_tip(&self, block: &Block) -> Result<(), BlockError> {
+        block.validate(self.tip())?;
+        block.check_size(self.max_block_bytes)?;
+        self.check_timestamp(block)
+    }
+
+    fn push(&mut self, block: Block, undo: UndoLog) {
+        let weight = self.fork_choice.block_weight(&block);
+        self.tree.insert(block.clone(), weight);
+        self.chain.push(block);
+        self.undo.push(undo);
+    }
+
+    // Extends the tip with a block whose transactions the caller has
+    // already executed, committing their `changes` to `state`
+    pub fn append(
+        &mut self,
+        block: Block,
+        state: &mut StateStore,
+        changes: ChangeSet,
+    ) -> Result<(), BlockError> {
+        self.check_extends_tip(&block)?;
+        let undo = state.commit(changes);
+        self.push(block, undo);
+        Ok(())
+    }
+
+    // Adds a block from anywhere in the tree. If fork choice then prefers its
+    // branch, `state` is moved onto it: blocks on the new branch are executed
+    // and the first invalid one is dropped with everything built on it,
+    // leaving the chain and state as they were. Side-branch blocks are only
+    // executed once their branch wins.
+    pub fn import_block(
+        &mut self,
+        block: Block,
+        state: &mut StateStore,
+    ) -> Result<ChainUpdate, BlockError> {
+        if self.tree.contains(&block.hash) {
+            return Ok(ChainUpdate::default());
+        }
+        let parent = self.tree.get(&block.header.previous_hash).ok_or_else(|| {
+            BlockError::UnknownParent {
+                hash: block.header.previous_hash.clone(),
+            }
+        })?;
+        block.validate(parent)?;
+        block.check_size(self.max_block_bytes)?;
+        let fork = parent.header.index;
+        if fork < self.pruned_below && self.chain[fork as usize].hash == parent.hash {
+            return Err(BlockError::ForkTooDeep {
+                fork,
+                tip: self.tip().header.index,
+            });
+        }
+        self.check_timestamp(&block)?;
+        self.check_drift(&block)?;
+        let hash = block.hash.clone();
+        let weight = self.fork_choice.block_weight(&block);
+        self.tree.insert(block, weight);
+        let block = self.tree.get(&hash).unwrap();
+        if !self.fork_choice.prefer(&self.tree, block, self.tip()) {
+            return Ok(ChainUpdate::default());
+        }
+        if block.header.previous_hash == self.tip().hash {
+            let block = block.clone();
+            let (receipts, changes) = match state.execute_block(&block, self.max_block_bytes) {
+                Ok(executed) => executed,
+                Err(e) => {
+                    self.tree.remove_subtree(&hash);
+                    return Err(e);
+                }
+            };
+            let undo = state.commit(changes.clone());
+            self.push(block.clone(), undo);
+            return Ok(ChainUpdate {
+                reverted: vec![],
+                applied: vec![block],
+                executed: vec![(receipts, changes)],
+            });
+        }
+        self.switch_to(&hash, state)
+    }
+
+    // Makes the branch ending at `tip` canonical: the canonical blocks above
+    // the fork point are unwound with their undo logs, then only the new
+    // branch's blocks are executed. If one of them is invalid, state is put
+    // back the way it was.
+    fn switch_to(&mut self, tip: &str, state: &mut StateStore) -> Result<ChainUpdate, BlockError> {
+        let mut branch = Vec::new();
+        let mut next = self.tree.get(tip);
+        while let Some(block) = next {
+            let index = block.header.index as usize;
+            if self.chain.get(index).is_some_and(|c| c.hash == block.hash) {
+                break;
+            }
+            branch.push(block.clone());
+            next = self.tree.get(&block.header.previous_hash);
+        }
+        branch.reverse();
+        // Genesis is shared by every branch, so the fork point is at least 0
+        let common = branch[0].header.index as usize;
+
+        let mut redo = Vec::new();
+        for undo in self.undo[common - 1..].iter().rev() {
+            redo.push(state.undo(undo));
+        }
+        let mut undo = Vec::with_capacity(branch.len());
+        let mut executed = Vec::with_capacity(branch.len());
+        for block in &branch {
+            match state.execute_block(block, self.max_block_bytes) {
+                Ok((receipts, changes)) => {
+                    undo.push(state.commit(changes.clone()));
+                    executed.push((receipts, changes));
+                }
+                Err(e) => {
+                    for log in undo.iter().rev() {
+                        state.undo(log);
+                    }
+                    for changes in redo.into_iter().rev() {
+                        state.commit(changes);
+                    }
+                    self.tree.remove_subtree(&block.hash);
+                    return Err(e);
+                }
+            }
+        }
+
+        let reverted = self.chain.split_off(common);
+        self.undo.truncate(common - 1);
+        self.chain.extend(branch.iter().cloned());
+        self.undo.extend(undo);
+        Ok(ChainUpdate {
+            reverted: reverted.into_iter().rev().collect(),
+            applied: branch,
+            executed,
+        })
+    }
+
+    // Takes back an update import_block just returned, e.g. because it could
+    // not be stored: the applied blocks are unwound, the reverted ones put
+    // back, and the imported block is forgotten so it can be tried again
+    pub fn rollback(&mut self, update: &ChainUpdate, state: &mut StateStore) {
+        let Some(imported) = update.applied.last() else {
+            return;
+        };
+        match update.reverted.first() {
+            Some(old_tip) => {
+                self.switch_to(&old_tip.hash, state)
+                    .expect("the old branch was valid before the update");
+            }
+            None => {
+                for _ in &update.applied {
+                    self.chain.pop();
+                    state.undo(&self.undo.pop().unwrap());
+                }
+            }
+        }
+        self.tree.remove_subtree(&imported.hash);
+    }
+
+    // Forgets side branches forking more than MAX_FORK_DEPTH blocks below
+    // the tip. Each height is visited once, as the tip moves past it. Call
+    // it once an update is stored: until then, rollback may need the branch
+    // the update left.
+    pub fn prune(&mut self) {
+        let horizon = self.tip().header.index.saturating_sub(MAX_FORK_DEPTH);
+        for height in self.pruned_below..horizon {
+            let parent = &self.chain[height as usize].hash;
+            let keep = &self.chain[height as usize + 1].hash;
+            self.tree.remove_siblings(parent, keep);
+        }
+        self.pruned_below = self.pruned_below.max(horizon);
     }
 
-    pub fn add_block(&mut self, transactions: Vec<Transaction>, timestamp: u64) -> bool {
-        let index = self.chain.len() as u64;
-        let previous_hash = self.latest_hash();
-        let new_block = Block::new(index, timestamp, transactions, previous_hash.clone());
-        let prev_block = self.chain.last().unwrap();
-        if new_block.is_valid(prev_block) {
-            self.chain.push(new_block);
-            true
-        } else {
-            false
+    // State as of canonical block `height`: `state`, which must be the state
+    // at the tip, with the undo log of every block above it unwound. None
+    // if the chain has no block at that height.
+    pub fn state_at(&self, state: &StateStore, height: u64) -> Option<StateStore> {
+        if height > self.tip().header.index {
+            return None;
+        }
+        let mut past = state.clone();
+        for log in self.undo[height as usize..].iter().rev() {
+            past.undo(log);
         }
+        Some(past)
+    }
+
+    // Marks a canonical block as final; FinalizedFirst never reorgs past it
+    pub fn finalize(&mut self, hash: &str) -> bool {
+        if !self.chain.iter().any(|block| block.hash == hash) {
+            return false;
+        }
+        self.tree.set_finalized(hash);
+        true
+    }
+
+    // Re-executes every block after genesis on top of `state`, which should
+    // hold the genesis allocation
+    pub fn replay(&self, state: &mut StateStore) -> Result<(), LoadError> {
+        for block in self.chain.iter().skip(1) {
+            state
+                .apply_block(block, self.max_block_bytes)
+                .map_err(|error| LoadError::InvalidBlock {
+                    index: block.header.index,
+                    error,
+                })?;
+        }
+        Ok(())
     }
 
     pub fn is_valid_chain(&self) -> bool {
@@ -112,104 +687,469 @@ impl Blockchain {
     }
 }
 
+// A block from Blockchain::build_block, with what the producer needs to
+// store it and commit its state changes once it is accepted
+#[derive(Debug, Clone)]
+pub struct BuiltBlock {
+    pub block: Block,
+    pub receipts: Vec<Receipt>,
+    pub changes: ChangeSet,
+    pub dropped: Vec<(Transaction, TxError)>,
+}
+
+// Pending transactions in arrival order, with their arrival times and
+// hashes. `hashes` holds the same hashes as `queue`, so a duplicate is
+// found without hashing every waiting transaction.
+#[derive(Default)]
+struct PendingTxs {
+    queue: Vec<(u64, TxHash, Transaction)>,
+    hashes: HashSet<TxHash>,
+}
+
+impl PendingTxs {
+    // Removes the first `count` transactions from the queue
+    fn take(&mut self, count: usize) -> Vec<Transaction> {
+        self.queue
+            .drain(..count)
+            .map(|(_, hash, tx)| {
+                self.hashes.remove(&hash);
+                tx
+            })
+            .collect()
+    }
+}
+
 pub struct Mempool {
-    pub txs: Arc<Mutex<Vec<Transaction>>>,
+    pub chain_id: String,
+    // Lowest fee admitted, from the chain spec's protocol parameters
+    pub min_fee: u64,
+    // The chain spec's max_block_bytes; a transaction whose canonical
+    // encoding is larger could never be included, so it is refused
+    pub max_block_bytes: u64,
+    // Seconds a transaction may wait before expire() drops it; None keeps
+    // transactions until they are drained
+    pub max_tx_age: Option<u64>,
+    // Stamps each transaction's arrival, in unix seconds
+    pub clock: Arc<dyn Clock>,
+    txs: Arc<Mutex<PendingTxs>>,
 }
 
 impl Mempool {
-    pub fn new() -> Self {
+    pub fn new(chain_id: &str) -> Self {
         Mempool {
-            txs: Arc::new(Mutex::new(Vec::new())),
+            chain_id: chain_id.to_string(),
+            min_fee: 0,
+            max_block_bytes: u64::MAX,
+            max_tx_age: None,
+            clock: Arc::new(SystemClock),
+            txs: Arc::new(Mutex::new(PendingTxs::default())),
+        }
+    }
+    // The stateless checks: chain, signature, minimum fee and whether the
+    // transaction fits in a block at all
+    pub fn check_tx(&self, tx: &Transaction) -> Result<(), TxError> {
+        tx.check(&self.chain_id)?;
+        if tx.fee < self.min_fee {
+            return Err(TxError::FeeTooLow {
+                minimum: self.min_fee,
+                got: tx.fee,
+            });
+        }
+        let size = tx.canonical_bytes().len() as u64;
+        if size > self.max_block_bytes {
+            return Err(TxError::TooLarge {
+                maximum: self.max_block_bytes,
+                got: size,
+            });
         }
+        Ok(())
     }
-    pub fn add_tx(&self, tx: Transaction) {
-        self.txs.lock().unwrap().push(tx);
+    // Only admits transactions that pass check_tx and are not already
+    // waiting; nonce and balance are checked when the transaction is applied
+    pub fn add_tx(&self, tx: Transaction) -> Result<TxHash, TxError> {
+        self.check_tx(&tx)?;
+        let hash = tx.hash();
+        let mut txs = self.txs.lock().unwrap();
+        if !txs.hashes.insert(hash) {
+            return Err(TxError::AlreadyKnown);
+        }
+        txs.queue.push((self.clock.now(), hash, tx));
+        Ok(hash)
+    }
+    // The transactions from `sender` still waiting, in arrival order
+    pub fn pending_from(&self, sender: &Address) -> Vec<Transaction> {
+        let txs = self.txs.lock().unwrap();
+        txs.queue
+            .iter()
+            .filter(|(_, _, tx)| tx.from == *sender)
+            .map(|(_, _, tx)| tx.clone())
+            .collect()
     }
     pub fn drain(&self) -> Vec<Transaction> {
         let mut txs = self.txs.lock().unwrap();
-        let drained = txs.clone();
-        txs.clear();
-        drained
+        let count = txs.queue.len();
+        txs.take(count)
     }
-}
-
-impl Default for Mempool {
-    fn default() -> Self {
-        Self::new()
+    // Takes transactions in arrival order while their canonical encodings
+    // fit in `max_bytes`; the rest wait for the next block
+    pub fn drain_block(&self, max_bytes: u64) -> Vec<Transaction> {
+        let mut txs = self.txs.lock().unwrap();
+        let mut size = 0u64;
+        let count = txs
+            .queue
+            .iter()
+            .take_while(|(_, _, tx)| {
+                size = size.saturating_add(tx.canonical_bytes().len() as u64);
+                size <= max_bytes
+            })
+            .count();
+        txs.take(count)
+    }
+    // Removes and returns transactions that have waited longer than
+    // max_tx_age
+    pub fn expire(&self) -> Vec<Transaction> {
+        let Some(max_age) = self.max_tx_age else {
+            return Vec::new();
+        };
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        let PendingTxs { queue, hashes } = &mut *self.txs.lock().unwrap();
+        queue.retain(|(received, hash, tx)| {
+            let keep = now.saturating_sub(*received) <= max_age;
+            if !keep {
+                hashes.remove(hash);
+                expired.push(tx.clone());
+            }
+            keep
+        });
+        expired
     }
 }
 
+// Blocks live in the default tree; receipts, account state and each block's
+// undo log in their own trees, all written together by store_block
 pub struct PersistentChain {
     pub db: Db,
+    pub receipts: sled::Tree,
+    pub balances: sled::Tree,
+    pub nonces: sled::Tree,
+    pub meta: sled::Tree,
+    pub undo: sled::Tree,
 }
 
+const BURNED_KEY: &[u8] = b"burned";
+
 impl Clone for PersistentChain {
     fn clone(&self) -> Self {
         PersistentChain {
             db: self.db.clone(),
+            receipts: self.receipts.clone(),
+            balances: self.balances.clone(),
+            nonces: self.nonces.clone(),
+            meta: self.meta.clone(),
+            undo: self.undo.clone(),
+        }
+    }
+}
+
+fn decode_u64(bytes: &[u8]) -> sled::Result<u64> {
+    let bytes =
+        <[u8; 8]>::try_from(bytes).map_err(|_| sled::Error::Corruption { at: None, bt: () })?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// Decodes a value read inside a transaction
+fn stored_u64(value: Option<sled::IVec>) -> ConflictableTransactionResult<Option<u64>, ()> {
+    value
+        .map(|bytes| decode_u64(&bytes))
+        .transpose()
+        .map_err(ConflictableTransactionError::Storage)
+}
+
+fn load_accounts(tree: &sled::Tree) -> sled::Result<HashMap<Address, u64>> {
+    tree.iter()
+        .map(|entry| {
+            let (key, value) = entry?;
+            let address = <[u8; 32]>::try_from(key.as_ref())
+                .map_err(|_| sled::Error::Corruption { at: None, bt: () })?;
+            Ok((Address(address), decode_u64(&value)?))
+        })
+        .collect()
+}
+
+// The trees of a PersistentChain inside one transaction
+struct BlockWriter<'a> {
+    blocks: &'a TransactionalTree,
+    receipts: &'a TransactionalTree,
+    balances: &'a TransactionalTree,
+    nonces: &'a TransactionalTree,
+    meta: &'a TransactionalTree,
+    undo: &'a TransactionalTree,
+}
+
+impl BlockWriter<'_> {
+    fn store(
+        &self,
+        block: &Block,
+        receipts: &[Receipt],
+        changes: &ChangeSet,
+    ) -> ConflictableTransactionResult<(), ()> {
+        let key = block.header.index.to_be_bytes();
+        self.blocks
+            .insert(&key, bincode::serialize(block).unwrap())?;
+        for (position, receipt) in receipts.iter().enumerate() {
+            let record = IndexedReceipt {
+                block_index: block.header.index,
+                position: position as u32,
+                receipt: receipt.clone(),
+            };
+            self.receipts
+                .insert(&receipt.tx_hash.0, bincode::serialize(&record).unwrap())?;
         }
+        let mut log = UndoLog::default();
+        for (address, balance) in &changes.balances {
+            let prior = self.balances.insert(&address.0, &balance.to_be_bytes())?;
+            log.balances.insert(*address, stored_u64(prior)?);
+        }
+        for (address, nonce) in &changes.nonces {
+            let prior = self.nonces.insert(&address.0, &nonce.to_be_bytes())?;
+            log.nonces.insert(*address, stored_u64(prior)?);
+        }
+        if let Some(burned) = changes.burned {
+            let prior = self.meta.insert(BURNED_KEY, &burned.to_be_bytes())?;
+            log.burned = Some(stored_u64(prior)?.unwrap_or(0));
+        }
+        self.undo.insert(&key, bincode::serialize(&log).unwrap())?;
+        Ok(())
+    }
+
+    fn revert(&self, block: &Block, log: &UndoLog) -> ConflictableTransactionResult<(), ()> {
+        let key = block.header.index.to_be_bytes();
+        for tx in &block.transactions {
+            self.receipts.remove(&tx.hash().0)?;
+        }
+        for (address, balance) in &log.balances {
+            match balance {
+                Some(balance) => self.balances.insert(&address.0, &balance.to_be_bytes())?,
+                None => self.balances.remove(&address.0)?,
+            };
+        }
+        for (address, nonce) in &log.nonces {
+            match nonce {
+                Some(nonce) => self.nonces.insert(&address.0, &nonce.to_be_bytes())?,
+                None => self.nonces.remove(&address.0)?,
+            };
+        }
+        if let Some(burned) = log.burned {
+            self.meta.insert(BURNED_KEY, &burned.to_be_bytes())?;
+        }
+        self.undo.remove(&key)?;
+        self.blocks.remove(&key)?;
+        Ok(())
     }
 }
 
 impl PersistentChain {
     pub fn open(path: &str) -> sled::Result<Self> {
-        let db = sled::open(path)?;
-        Ok(PersistentChain { db })
+        Self::from_db(sled::open(path)?)
     }
-    pub fn store_block(&self, block: &Block) -> sled::Result<()> {
-        let key = block.index.to_be_bytes();
-        let value = bincode::serialize(block).unwrap();
-        self.db.insert(key, value)?;
-        Ok(())
+
+    // A database deleted when the last handle to it is dropped, for
+    // simulations
+    pub fn temporary() -> sled::Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
     }
-    pub fn get_block(&self, index: u64) -> Option<Block> {
-        let key = index.to_be_bytes();
-        self.db
-            .get(key)
-            .ok()
-            .flatten()
+
+    fn from_db(db: Db) -> sled::Result<Self> {
+        let receipts = db.open_tree("receipts")?;
+        let balances = db.open_tree("balances")?;
+        let nonces = db.open_tree("nonces")?;
+        let meta = db.open_tree("meta")?;
+        let undo = db.open_tree("undo")?;
+        Ok(PersistentChain {
+            db,
+            receipts,
+            balances,
+            nonces,
+            meta,
+            undo,
+        })
+    }
+    // Runs `write` as one sled transaction over every tree, so it lands
+    // completely or not at all
+    fn write(
+        &self,
+        write: impl Fn(&BlockWriter) -> ConflictableTransactionResult<(), ()>,
+    ) -> sled::Result<()> {
+        let blocks: &sled::Tree = &self.db;
+        (
+            blocks,
+            &self.receipts,
+            &self.balances,
+            &self.nonces,
+            &self.meta,
+            &self.undo,
+        )
+            .transaction(|(blocks, receipts, balances, nonces, meta, undo)| {
+                write(&BlockWriter {
+                    blocks,
+                    receipts,
+                    balances,
+                    nonces,
+                    meta,
+                    undo,
+                })
+            })
+            .map_err(|e: TransactionError<()>| match e {
+                TransactionError::Storage(e) => e,
+                TransactionError::Abort(()) => unreachable!("block writes never abort"),
+            })
+    }
+
+    fn get_undo(&self, index: u64) -> Result<UndoLog, LoadError> {
+        self.undo
+            .get(index.to_be_bytes())?
             .and_then(|ivec| bincode::deserialize(&ivec).ok())
+            .ok_or(LoadError::MissingUndo(index))
     }
-}
 
-pub struct StateStore {
-    pub balances: HashMap<String, u64>,
-    pub nonces: HashMap<String, u64>,
-}
+    // Writes a block, the receipts of its transactions and the state changes
+    // they made in one transaction, so disk never holds one without the other.
+    // The values the changes overwrite are kept as the block's undo log.
+    pub fn store_block(
+        &self,
+        block: &Block,
+        receipts: &[Receipt],
+        changes: &ChangeSet,
+    ) -> sled::Result<()> {
+        self.write(|writer| writer.store(block, receipts, changes))
+    }
 
-impl StateStore {
-    pub fn new() -> Self {
-        StateStore {
-            balances: HashMap::new(),
-            nonces: HashMap::new(),
+    // Rewinds the database so block `height` is the head again, undoing the
+    // blocks above it newest first. Each block goes in its own transaction,
+    // so an interrupted rewind leaves a shorter but consistent chain.
+    pub fn revert_to(&self, height: u64) -> Result<(), LoadError> {
+        let Some(head) = self.head()? else {
+            return Ok(());
+        };
+        for index in (height + 1..=head).rev() {
+            let block = self
+                .get_block(index)
+                .ok_or(LoadError::MissingBlock(index))?;
+            let log = self.get_undo(index)?;
+            self.write(|writer| writer.revert(&block, &log))?;
         }
+        Ok(())
     }
-    pub fn apply_tx(&mut self, tx: &Transaction) -> bool {
-        // Dummy signature check
-        if tx.signature.is_none() {
-            return false;
+
+    // Brings the database in line with a ChainUpdate from
+    // Blockchain::import_block in one transaction: the stored blocks from
+    // the first applied height up are undone, then each applied block is
+    // stored with the receipts and changes the import produced. If the write
+    // fails, the database is left as it was.
+    pub fn apply_update(&self, update: &ChainUpdate) -> Result<(), LoadError> {
+        let Some(first) = update.applied.first() else {
+            return Ok(());
+        };
+        let mut reverted = Vec::new();
+        if let Some(head) = self.head()? {
+            for index in (first.header.index..=head).rev() {
+                let block = self
+                    .get_block(index)
+                    .ok_or(LoadError::MissingBlock(index))?;
+                reverted.push((block, self.get_undo(index)?));
+            }
         }
-        // Nonce check
-        let nonce = self.nonces.get(&tx.from).cloned().unwrap_or(0);
-        if tx.nonce != nonce {
-            return false;
+        self.write(|writer| {
+            for (block, log) in &reverted {
+                writer.revert(block, log)?;
+            }
+            for (block, (receipts, changes)) in update.applied.iter().zip(&update.executed) {
+                writer.store(block, receipts, changes)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Index of the highest stored block
+    pub fn head(&self) -> sled::Result<Option<u64>> {
+        self.db.last()?.map(|(key, _)| decode_u64(&key)).transpose()
+    }
+
+    // Reads back every stored block with its undo log, checking that each
+    // block extends the one before it, fits in `max_block_bytes` and that
+    // the chain starts at this network's `genesis`
+    pub fn load_chain(
+        &self,
+        genesis: &Block,
+        max_block_bytes: u64,
+    ) -> Result<Blockchain, LoadError> {
+        let mut chain = Blockchain::from_genesis(genesis.clone(), LongestChain);
+        chain.max_block_bytes = max_block_bytes;
+        let Some(head) = self.head()? else {
+            return Ok(chain);
+        };
+        let stored = self.get_block(0).ok_or(LoadError::MissingBlock(0))?;
+        if stored.hash != chain.chain[0].hash {
+            return Err(LoadError::GenesisMismatch {
+                expected: chain.chain[0].hash.clone(),
+                got: stored.hash,
+            });
         }
-        // Balance check
-        let bal = self.balances.get(&tx.from).cloned().unwrap_or(0);
-        if bal < tx.amount {
-            return false;
+        for index in 1..=head {
+            let block = self
+                .get_block(index)
+                .ok_or(LoadError::MissingBlock(index))?;
+            chain
+                .check_extends_tip(&block)
+                .map_err(|error| LoadError::InvalidBlock { index, error })?;
+            chain.push(block, self.get_undo(index)?);
         }
-        // Apply
-        *self.balances.entry(tx.from.clone()).or_insert(0) -= tx.amount;
-        *self.balances.entry(tx.to.clone()).or_insert(0) += tx.amount;
-        self.nonces.insert(tx.from.clone(), tx.nonce + 1);
-        true
+        Ok(chain)
     }
-}
 
-impl Default for StateStore {
-    fn default() -> Self {
-        Self::new()
+    // Audit: re-executes `chain` from `genesis_state` and checks the result
+    // against the stored state, returning the recomputed state
+    pub fn reexecute(
+        &self,
+        chain: &Blockchain,
+        mut genesis_state: StateStore,
+    ) -> Result<StateStore, LoadError> {
+        chain.replay(&mut genesis_state)?;
+        if self.load_state(&genesis_state.chain_id)? != genesis_state {
+            return Err(LoadError::StateMismatch);
+        }
+        Ok(genesis_state)
+    }
+
+    // Account state as of the last stored block
+    pub fn load_state(&self, chain_id: &str) -> sled::Result<StateStore> {
+        let mut state = StateStore::new(chain_id);
+        state.commit(ChangeSet {
+            balances: load_accounts(&self.balances)?,
+            nonces: load_accounts(&self.nonces)?,
+            burned: None,
+        });
+        if let Some(burned) = self.meta.get(BURNED_KEY)? {
+            state.burned = decode_u64(&burned)?;
+        }
+        Ok(state)
+    }
+    pub fn get_receipt(&self, tx_hash: &TxHash) -> Option<IndexedReceipt> {
+        self.receipts
+            .get(tx_hash.0)
+            .ok()
+            .flatten()
+            .and_then(|ivec| bincode::deserialize(&ivec).ok())
+    }
+    pub fn get_block(&self, index: u64) -> Option<Block> {
+        let key = index.to_be_bytes();
+        self.db
+            .get(key)
+            .ok()
+            .flatten()
+            .and_then(|ivec| bincode::deserialize(&ivec).ok())
     }
 }
 
@@ -246,71 +1186,75 @@ impl Default for WasmEngine {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Token {
-    pub name: String,
-    pub symbol: String,
-    pub total_supply: u64,
-    pub balances: HashMap<String, u64>,
+// Secret key material is zeroized when the wallet is dropped and never
+// serialized; use keystore::Keystore to put it on disk
+#[derive(Debug, Clone)]
+pub struct Wallet {
+    pub address: Address,
+    private_key: SigningKey,
 }
 
-impl Token {
-    pub fn new(name: &str, symbol: &str, total_supply: u64) -> Self {
-        let mut balances = HashMap::new();
-        balances.insert("genesis".to_string(), total_supply);
-        Token {
-            name: name.to_string(),
-            symbol: symbol.to_string(),
-            total_supply,
-            balances,
-        }
+impl Wallet {
+    pub fn generate() -> Self {
+        Wallet::from_signing_key(SigningKey::generate(&mut rand::rngs::OsRng))
     }
 
-    pub fn transfer(&mut self, from: &str, to: &str, amount: u64) -> Result<(), String> {
-        let from_balance = self.balances.get(from).cloned().unwrap_or(0);
-        if from_balance < amount {
-            return Err("Insufficient balance".to_string());
-        }
-        *self.balances.entry(from.to_string()).or_insert(0) -= amount;
-        *self.balances.entry(to.to_string()).or_insert(0) += amount;
-        Ok(())
+    pub fn from_secret_key(secret_key: &[u8; 32]) -> Self {
+        Wallet::from_signing_key(SigningKey::from_bytes(secret_key))
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Wallet {
-    pub address: String,
-    pub private_key: String,
-}
+    pub fn from_secret_hex(secret_hex: &str) -> anyhow::Result<Self> {
+        let bytes = Zeroizing::new(hex::decode(secret_hex)?);
+        let secret: Zeroizing<[u8; 32]> = Zeroizing::new(
+            bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("secret key must be 32 bytes"))?,
+        );
+        Ok(Wallet::from_secret_key(&secret))
+    }
 
-impl Wallet {
-    pub fn new(address: &str, private_key: &str) -> Self {
+    fn from_signing_key(private_key: SigningKey) -> Self {
         Wallet {
-            address: address.to_string(),
-            private_key: private_key.to_string(),
+            address: Address::from_public_key(&private_key.verifying_key()),
+            private_key,
         }
     }
 
+    pub fn public_key(&self) -> VerifyingKey {
+        self.private_key.verifying_key()
+    }
+
+    pub fn secret_key(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.private_key.to_bytes())
+    }
+
     pub fn sign_transaction(&self, tx: &mut Transaction) {
-        tx.signature = Some(format!("signed_by_{}", self.address));
+        tx.signature = Some(self.private_key.sign(&tx.signing_bytes()));
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use genesis::{COIN, DEVNET_CHAIN_ID, MAX_SUPPLY, TESTNET_CHAIN_ID};
+    use receipt::{Event, TxStatus};
     #[test]
-    fn test_blockchain_add_block() {
+    fn test_blockchain_append() {
         let mut chain = Blockchain::new();
         let tx = Transaction {
-            from: "a".into(),
-            to: "b".into(),
+            chain_id: DEVNET_CHAIN_ID.into(),
+            from: Address([0xa; 32]),
+            to: Address([0xb; 32]),
             amount: 10,
+            fee: 0,
             nonce: 1,
-            signature: Some("sig".into()),
+            signature: None,
         };
-        let ok = chain.add_block(vec![tx.clone()], 123);
-        assert!(ok);
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        let block = chain.next_block(vec![tx.clone()], &[], 123, ZERO_HASH.into(), "p".into());
+        let added = chain.append(block, &mut state, ChangeSet::default());
+        assert_eq!(added, Ok(()));
         assert_eq!(chain.chain.len(), 2);
         assert_eq!(chain.chain[1].transactions[0], tx);
     }
@@ -319,21 +1263,30 @@ mod tests {
     fn test_chain_validation() {
         let mut chain = Blockchain::new();
         let tx1 = Transaction {
-            from: "a".into(),
-            to: "b".into(),
+            chain_id: DEVNET_CHAIN_ID.into(),
+            from: Address([0xa; 32]),
+            to: Address([0xb; 32]),
             amount: 10,
+            fee: 0,
             nonce: 1,
-            signature: Some("sig1".into()),
+            signature: None,
         };
         let tx2 = Transaction {
-            from: "b".into(),
-            to: "c".into(),
+            chain_id: DEVNET_CHAIN_ID.into(),
+            from: Address([0xb; 32]),
+            to: Address([0xc; 32]),
             amount: 5,
+            fee: 0,
             nonce: 2,
-            signature: Some("sig2".into()),
+            signature: None,
         };
-        chain.add_block(vec![tx1], 1);
-        chain.add_block(vec![tx2], 2);
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        for (tx, timestamp) in [(tx1, 1), (tx2, 2)] {
+            let block = chain.next_block(vec![tx], &[], timestamp, ZERO_HASH.into(), "p".into());
+            chain
+                .append(block, &mut state, ChangeSet::default())
+                .unwrap();
+        }
         assert!(chain.is_valid_chain());
     }
 
@@ -341,15 +1294,942 @@ mod tests {
     fn test_invalid_block() {
         let mut chain = Blockchain::new();
         let tx = Transaction {
-            from: "a".into(),
-            to: "b".into(),
+            chain_id: DEVNET_CHAIN_ID.into(),
+            from: Address([0xa; 32]),
+            to: Address([0xb; 32]),
             amount: 10,
+            fee: 0,
             nonce: 1,
-            signature: Some("sig".into()),
+            signature: None,
         };
-        let mut block = Block::new(1, 123, vec![tx], "bad_hash".into());
+        let mut block = Block::new(
+            1,
+            123,
+            vec![tx],
+            &[],
+            "bad_hash".into(),
+            ZERO_HASH.into(),
+            "p".into(),
+        );
         block.hash = "tampered".into();
         chain.chain.push(block);
         assert!(!chain.is_valid_chain());
     }
+
+    // RFC 8032 test 1 key pair
+    const VECTOR_SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const VECTOR_PUBLIC_KEY: &str =
+        "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const BOB: Address = Address([0xbb; 32]);
+
+    fn vector_address() -> Address {
+        Wallet::from_secret_hex(VECTOR_SECRET).unwrap().address
+    }
+
+    fn signed_tx(nonce: u64) -> Transaction {
+        let wallet = Wallet::from_secret_hex(VECTOR_SECRET).unwrap();
+        let mut tx = Transaction {
+            chain_id: DEVNET_CHAIN_ID.into(),
+            from: wallet.address,
+            to: BOB,
+            amount: 42,
+            fee: 1,
+            nonce,
+            signature: None,
+        };
+        wallet.sign_transaction(&mut tx);
+        tx
+    }
+
+    fn vector_tx() -> Transaction {
+        signed_tx(7)
+    }
+
+    #[test]
+    fn test_transaction_encoding_vectors() {
+        let tx = vector_tx();
+        assert_eq!(
+            encoding::sha256_hex(&tx.signing_bytes()),
+            "a0c1d958026846874d940a8d4e5c7b9005693dcb6907ff9e6e31e865407d1fd0"
+        );
+        assert_eq!(
+            encoding::sha256_hex(&tx.canonical_bytes()),
+            "28ba686ebd4355d1f160dc327cc3fd46b5f358070de98ea2b8d9b42f629b08d2"
+        );
+    }
+
+    #[test]
+    fn test_block_hash_vectors() {
+        let genesis = Blockchain::new().chain.remove(0);
+        assert_eq!(
+            genesis.header.tx_root,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            genesis.hash,
+            "a3e14da6102b2641b4231998d969e32c9825e82b3dd6697c09de73dbbea037a3"
+        );
+        let block = Block::new(
+            1,
+            1_700_000_000,
+            vec![vector_tx()],
+            &[],
+            genesis.hash.clone(),
+            ZERO_HASH.into(),
+            "validator-1".into(),
+        );
+        assert_eq!(
+            block.header.tx_root,
+            "9bcf91f4c3e6c66004e8386609d59928d3d293108f559832f65ef72b5cf615be"
+        );
+        assert_eq!(
+            block.hash,
+            "ace3e54ec28e0caedb966bd3f52b766d150028cf6ed166152fa06a951e41bb56"
+        );
+    }
+
+    #[test]
+    fn test_block_hash_fields_do_not_run_together() {
+        let header = |index, timestamp| BlockHeader {
+            index,
+            timestamp,
+            previous_hash: "0".into(),
+            tx_root: ZERO_HASH.into(),
+            receipts_root: ZERO_HASH.into(),
+            state_root: ZERO_HASH.into(),
+            proposer: String::new(),
+            spec_hash: None,
+        };
+        assert_ne!(header(1, 23).hash(), header(12, 3).hash());
+    }
+
+    #[test]
+    fn test_tx_inclusion_proof() {
+        let txs: Vec<Transaction> = (0..5)
+            .map(|nonce| Transaction {
+                nonce,
+                ..vector_tx()
+            })
+            .collect();
+        let block = Block::new(
+            1,
+            1,
+            txs.clone(),
+            &[],
+            "0".into(),
+            ZERO_HASH.into(),
+            "p".into(),
+        );
+        for (i, tx) in txs.iter().enumerate() {
+            let proof = block.tx_proof(i).unwrap();
+            assert!(block.header.verify_tx(tx, &proof));
+        }
+        let proof = block.tx_proof(1).unwrap();
+        assert!(!block.header.verify_tx(&txs[2], &proof));
+        assert!(block.tx_proof(5).is_none());
+    }
+
+    #[test]
+    fn test_tampered_transactions_invalidate_block() {
+        let chain = Blockchain::new();
+        let mut block = Block::new(
+            1,
+            1,
+            vec![vector_tx()],
+            &[],
+            chain.latest_hash(),
+            ZERO_HASH.into(),
+            "p".into(),
+        );
+        assert!(block.is_valid(&chain.chain[0]));
+        block.transactions[0].amount = 1_000;
+        assert_eq!(
+            block.validate(&chain.chain[0]),
+            Err(BlockError::TxRootMismatch)
+        );
+    }
+
+    #[test]
+    fn test_block_errors() {
+        let chain = Blockchain::new();
+        let genesis = &chain.chain[0];
+        let block = |index, previous_hash: &str| {
+            Block::new(
+                index,
+                1,
+                vec![],
+                &[],
+                previous_hash.into(),
+                ZERO_HASH.into(),
+                "p".into(),
+            )
+        };
+        assert_eq!(
+            block(2, &genesis.hash).validate(genesis),
+            Err(BlockError::WrongIndex {
+                expected: 1,
+                got: 2
+            })
+        );
+        assert_eq!(
+            block(1, ZERO_HASH).validate(genesis),
+            Err(BlockError::WrongPreviousHash {
+                expected: genesis.hash.clone(),
+                got: ZERO_HASH.into()
+            })
+        );
+        let mut tampered = block(1, &genesis.hash);
+        tampered.header.proposer = "q".into();
+        assert_eq!(tampered.validate(genesis), Err(BlockError::HashMismatch));
+        let mut respec = block(1, &genesis.hash);
+        respec.header.spec_hash = Some(ZERO_HASH.into());
+        respec.hash = respec.header.hash();
+        assert_eq!(
+            respec.validate(genesis),
+            Err(BlockError::UnexpectedSpecHash)
+        );
+        assert_eq!(block(1, &genesis.hash).validate(genesis), Ok(()));
+    }
+
+    fn funded_state(account: Address, balance: u64) -> StateStore {
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.commit(ChangeSet {
+            balances: HashMap::from([(account, balance)]),
+            ..ChangeSet::default()
+        });
+        state
+    }
+
+    #[test]
+    fn test_apply_tx_receipts() {
+        let mut state = funded_state(vector_address(), 50);
+        let tx = signed_tx(0);
+        let receipt = state.apply_tx(&tx).unwrap();
+        assert_eq!(receipt.tx_hash, tx.hash());
+        assert_eq!(receipt.status, TxStatus::Success);
+        assert_eq!(receipt.fee_paid, 1);
+        assert_eq!(
+            receipt.events,
+            vec![
+                Event::FeeBurned {
+                    from: vector_address(),
+                    amount: 1
+                },
+                Event::Transfer {
+                    from: vector_address(),
+                    to: BOB,
+                    amount: 42
+                },
+            ]
+        );
+        assert_eq!(state.balance(&vector_address()), 7);
+        assert_eq!(state.balance(&BOB), 42);
+
+        // Can pay the fee but not the amount: included as failed
+        let tx = signed_tx(1);
+        let receipt = state.apply_tx(&tx).unwrap();
+        assert_eq!(receipt.status, TxStatus::Failed);
+        assert_eq!(
+            receipt.failure_reason.as_deref(),
+            Some("insufficient_balance")
+        );
+        assert_eq!(state.balance(&vector_address()), 6);
+        assert_eq!(state.nonce(&vector_address()), 2);
+
+        // Wrong nonce: not includable
+        assert_eq!(
+            state.apply_tx(&tx),
+            Err(TxError::NonceTooLow {
+                expected: 2,
+                got: 1
+            })
+        );
+        assert_eq!(
+            state.apply_tx(&signed_tx(3)),
+            Err(TxError::NonceTooHigh {
+                expected: 2,
+                got: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_apply_tx_rejects_unpayable_fee_and_overflow() {
+        let mut state = funded_state(vector_address(), 0);
+        assert_eq!(
+            state.apply_tx(&signed_tx(0)),
+            Err(TxError::InsufficientBalance {
+                needed: 1,
+                available: 0
+            })
+        );
+
+        let mut state = funded_state(vector_address(), 50);
+        state.commit(ChangeSet {
+            balances: HashMap::from([(BOB, u64::MAX)]),
+            ..ChangeSet::default()
+        });
+        assert_eq!(state.apply_tx(&signed_tx(0)), Err(TxError::Overflow));
+        assert_eq!(state.balance(&vector_address()), 50);
+        assert!(!state.nonces().contains_key(&vector_address()));
+    }
+
+    #[test]
+    fn test_tx_error_reason_matches_serialized_tag() {
+        let errors = [
+            TxError::WrongChain {
+                expected: "a".into(),
+                got: "b".into(),
+            },
+            TxError::MissingSignature,
+            TxError::BadSignature,
+            TxError::NonceTooLow {
+                expected: 2,
+                got: 1,
+            },
+            TxError::NonceTooHigh {
+                expected: 1,
+                got: 2,
+            },
+            TxError::InsufficientBalance {
+                needed: 2,
+                available: 1,
+            },
+            TxError::Overflow,
+            TxError::FeeTooLow { minimum: 2, got: 1 },
+            TxError::TooLarge { maximum: 1, got: 2 },
+            TxError::AlreadyKnown,
+        ];
+        for error in errors {
+            let json = serde_json::to_value(&error).unwrap();
+            assert_eq!(json["reason"], error.reason());
+        }
+    }
+
+    #[test]
+    fn test_allocation_is_capped_at_max_supply() {
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.allocate(vector_address(), MAX_SUPPLY - 10).unwrap();
+        state.allocate(BOB, 10).unwrap();
+        assert_eq!(state.allocate(BOB, 1), Err(TxError::Overflow));
+        assert_eq!(state.total_supply(), Some(MAX_SUPPLY));
+
+        // Burned fees still count towards the supply
+        state.apply_tx(&signed_tx(0)).unwrap();
+        assert_eq!(state.burned, 1);
+        assert_eq!(state.total_supply(), Some(MAX_SUPPLY));
+        assert_eq!(state.allocate(BOB, 1), Err(TxError::Overflow));
+        assert_eq!(
+            state.supply(),
+            Some(Supply {
+                max: MAX_SUPPLY,
+                total: MAX_SUPPLY,
+                burned: 1,
+                circulating: MAX_SUPPLY - 1,
+            })
+        );
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(32))]
+
+        // Whatever mix of transfers, failed transfers and fees is applied,
+        // balances plus burned fees never move off the allocated supply
+        #[test]
+        fn prop_transfers_conserve_supply(
+            split in proptest::collection::vec(0..=MAX_SUPPLY / 4, 3),
+            txs in proptest::collection::vec(
+                (0..4usize, 0..4usize, 0..=MAX_SUPPLY, 0..=1_000 * COIN),
+                1..20,
+            ),
+        ) {
+            let wallets: Vec<Wallet> = (1..=4u8).map(|i| Wallet::from_secret_key(&[i; 32])).collect();
+            let mut state = StateStore::new(DEVNET_CHAIN_ID);
+            let rest = MAX_SUPPLY - split.iter().sum::<u64>();
+            for (wallet, amount) in wallets.iter().zip(split.into_iter().chain([rest])) {
+                state.allocate(wallet.address, amount).unwrap();
+            }
+            proptest::prop_assert_eq!(state.total_supply(), Some(MAX_SUPPLY));
+            for (from, to, amount, fee) in txs {
+                let sender = &wallets[from];
+                let mut tx = Transaction {
+                    chain_id: DEVNET_CHAIN_ID.into(),
+                    from: sender.address,
+                    to: wallets[to].address,
+                    amount,
+                    fee,
+                    nonce: state.nonce(&sender.address),
+                    signature: None,
+                };
+                sender.sign_transaction(&mut tx);
+                let _ = state.apply_tx(&tx);
+                proptest::prop_assert_eq!(state.total_supply(), Some(MAX_SUPPLY));
+                proptest::prop_assert_eq!(state.circulating_supply(), Some(MAX_SUPPLY - state.burned));
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_tx_rejects_bad_signatures() {
+        let mut state = funded_state(vector_address(), 50);
+
+        let mut unsigned = signed_tx(0);
+        unsigned.signature = None;
+        assert_eq!(state.apply_tx(&unsigned), Err(TxError::MissingSignature));
+
+        // Changing any signed field invalidates the signature
+        let mut tampered = signed_tx(0);
+        tampered.amount = 49;
+        assert_eq!(state.apply_tx(&tampered), Err(TxError::BadSignature));
+
+        // Signed by someone other than the sender
+        let mut forged = signed_tx(0);
+        Wallet::generate().sign_transaction(&mut forged);
+        assert_eq!(state.apply_tx(&forged), Err(TxError::BadSignature));
+
+        assert_eq!(state.balance(&vector_address()), 50);
+        assert!(state.apply_tx(&signed_tx(0)).is_ok());
+    }
+
+    #[test]
+    fn test_replay_protection() {
+        let wallet = Wallet::from_secret_hex(VECTOR_SECRET).unwrap();
+        let mut foreign = Transaction {
+            chain_id: TESTNET_CHAIN_ID.into(),
+            ..signed_tx(0)
+        };
+        wallet.sign_transaction(&mut foreign);
+        assert!(foreign.verify_signature());
+
+        let mut state = funded_state(vector_address(), 50);
+        let wrong_chain = TxError::WrongChain {
+            expected: DEVNET_CHAIN_ID.into(),
+            got: TESTNET_CHAIN_ID.into(),
+        };
+        assert_eq!(state.apply_tx(&foreign), Err(wrong_chain.clone()));
+        assert!(state.apply_tx(&signed_tx(0)).is_ok());
+
+        let mempool = Mempool::new(DEVNET_CHAIN_ID);
+        assert_eq!(mempool.add_tx(foreign), Err(wrong_chain));
+        assert_eq!(mempool.add_tx(signed_tx(1)), Ok(signed_tx(1).hash()));
+        let mut unsigned = signed_tx(2);
+        unsigned.signature = None;
+        assert_eq!(mempool.add_tx(unsigned), Err(TxError::MissingSignature));
+        assert_eq!(mempool.drain().len(), 1);
+
+        // The chain id is covered by the signature
+        let mut relabelled = signed_tx(1);
+        relabelled.chain_id = TESTNET_CHAIN_ID.into();
+        assert!(!relabelled.verify_signature());
+    }
+
+    // An empty block on `parent`; with no accounts the state root stays zero
+    fn empty_child(parent: &Block, timestamp: u64) -> Block {
+        Block::new(
+            parent.header.index + 1,
+            timestamp,
+            vec![],
+            &[],
+            parent.hash.clone(),
+            ZERO_HASH.into(),
+            "p".into(),
+        )
+    }
+
+    #[test]
+    fn test_block_timestamp_rules() {
+        let clock = Arc::new(clock::ManualClock::new(1_000));
+        let mut chain = Blockchain::new();
+        chain.clock = clock.clone();
+        let genesis = StateStore::new(DEVNET_CHAIN_ID);
+        let mut state = genesis.clone();
+
+        let stale = empty_child(chain.tip(), 0);
+        assert_eq!(
+            chain.import_block(stale, &mut state).unwrap_err(),
+            BlockError::TimestampTooEarly {
+                median_time_past: 0,
+                got: 0
+            }
+        );
+
+        // Genesis at 0, then 10, 20, ..., 100: the median of the last eleven
+        // is 50, so a block may go back before its parent's 100 as long as it
+        // stays after 50
+        for i in 1..=10 {
+            let block = empty_child(chain.tip(), i * 10);
+            chain.import_block(block, &mut state).unwrap();
+        }
+        assert_eq!(chain.median_time_past(&chain.latest_hash()), Some(50));
+        assert_eq!(
+            chain.append(
+                empty_child(chain.tip(), 50),
+                &mut state,
+                ChangeSet::default()
+            ),
+            Err(BlockError::TimestampTooEarly {
+                median_time_past: 50,
+                got: 50
+            })
+        );
+        assert_eq!(chain.next_timestamp(0), 51);
+        assert_eq!(chain.next_timestamp(1_000), 1_000);
+        let block = empty_child(chain.tip(), 51);
+        chain.import_block(block, &mut state).unwrap();
+
+        let early = empty_child(chain.tip(), 1_000 + MAX_FUTURE_DRIFT + 1);
+        assert_eq!(
+            chain.import_block(early.clone(), &mut state).unwrap_err(),
+            BlockError::TimestampTooFarAhead {
+                latest: 1_000 + MAX_FUTURE_DRIFT,
+                got: early.header.timestamp
+            }
+        );
+        // Rejected blocks are not kept, so the same block is accepted once
+        // local time catches up
+        assert!(!chain.tree.contains(&early.hash));
+        clock.advance(1);
+        chain.import_block(early, &mut state).unwrap();
+        // Stored and locally produced blocks skip the drift check
+        chain
+            .append(
+                empty_child(chain.tip(), u64::MAX),
+                &mut state,
+                ChangeSet::default(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_mempool_applies_protocol_params() {
+        let mut mempool = Mempool::new(DEVNET_CHAIN_ID);
+        mempool.min_fee = 2;
+        assert_eq!(
+            mempool.add_tx(signed_tx(0)),
+            Err(TxError::FeeTooLow { minimum: 2, got: 1 })
+        );
+        mempool.min_fee = 1;
+        for nonce in 0..3 {
+            mempool.add_tx(signed_tx(nonce)).unwrap();
+        }
+        assert_eq!(mempool.add_tx(signed_tx(1)), Err(TxError::AlreadyKnown));
+        let size = signed_tx(0).canonical_bytes().len() as u64;
+        // A transaction that could never fit in a block is refused, so it
+        // cannot hold up the queue
+        mempool.max_block_bytes = size - 1;
+        assert_eq!(
+            mempool.add_tx(signed_tx(3)),
+            Err(TxError::TooLarge {
+                maximum: size - 1,
+                got: size
+            })
+        );
+        // Two fit, the third waits for the next block
+        let first = mempool.drain_block(2 * size + size / 2);
+        assert_eq!(first, vec![signed_tx(0), signed_tx(1)]);
+        assert_eq!(mempool.drain_block(size), vec![signed_tx(2)]);
+        assert!(mempool.drain().is_empty());
+        // Drained transactions are no longer known
+        mempool.max_block_bytes = size;
+        assert!(mempool.add_tx(signed_tx(0)).is_ok());
+        assert_eq!(mempool.pending_from(&vector_address()), vec![signed_tx(0)]);
+        assert!(mempool.pending_from(&Address([0xbb; 32])).is_empty());
+    }
+
+    #[test]
+    fn test_mempool_expires_old_transactions() {
+        let clock = Arc::new(clock::ManualClock::new(100));
+        let mut mempool = Mempool::new(DEVNET_CHAIN_ID);
+        mempool.clock = clock.clone();
+        mempool.add_tx(signed_tx(0)).unwrap();
+        clock.advance(5);
+        mempool.add_tx(signed_tx(1)).unwrap();
+        clock.advance(6);
+        // Without a limit nothing expires
+        assert!(mempool.expire().is_empty());
+        mempool.max_tx_age = Some(10);
+        assert_eq!(mempool.expire(), vec![signed_tx(0)]);
+        assert_eq!(mempool.add_tx(signed_tx(1)), Err(TxError::AlreadyKnown));
+        assert_eq!(mempool.drain(), vec![signed_tx(1)]);
+        // Expired and drained transactions may be submitted again
+        assert!(mempool.add_tx(signed_tx(0)).is_ok());
+        assert!(mempool.add_tx(signed_tx(1)).is_ok());
+    }
+
+    #[test]
+    fn test_build_block_leaves_out_failing_txs() {
+        let mut chain = Blockchain::new();
+        chain.clock = Arc::new(clock::ManualClock::new(7));
+        let mut state = funded_state(vector_address(), 50);
+        let before = state.state_root();
+        let built = chain.build_block(&state, vec![signed_tx(0), signed_tx(2)], "p".into());
+        assert_eq!(state.state_root(), before);
+        assert_eq!(built.block.transactions, vec![signed_tx(0)]);
+        assert_eq!(built.block.header.timestamp, 7);
+        assert_eq!(built.receipts.len(), 1);
+        assert_eq!(
+            built.dropped,
+            vec![(
+                signed_tx(2),
+                TxError::NonceTooHigh {
+                    expected: 1,
+                    got: 2
+                }
+            )]
+        );
+        chain
+            .append(built.block.clone(), &mut state, built.changes)
+            .unwrap();
+        assert_eq!(state.state_root(), built.block.header.state_root);
+    }
+
+    #[test]
+    fn test_block_one_byte_over_the_limit_is_rejected() {
+        let state = funded_state(vector_address(), 50);
+        let mut chain = Blockchain::new();
+        let built = chain.build_block(&state, vec![signed_tx(0), signed_tx(1)], "p".into());
+        let block = built.block;
+        let size = block.size();
+        assert_eq!(size, 2 * signed_tx(0).canonical_bytes().len() as u64);
+        let too_large = Err(BlockError::TooLarge {
+            maximum: size - 1,
+            got: size,
+        });
+
+        // Executed, appended after local production, imported from a peer
+        // or read back from the database, the limit is the same
+        assert_eq!(state.execute_block(&block, size - 1).map(|_| ()), too_large);
+        chain.max_block_bytes = size - 1;
+        let mut appended = state.clone();
+        assert_eq!(
+            chain.append(block.clone(), &mut appended, built.changes.clone()),
+            too_large
+        );
+        let mut imported = state.clone();
+        assert_eq!(
+            chain.import_block(block.clone(), &mut imported).map(|_| ()),
+            too_large
+        );
+        assert!(!chain.tree.contains(&block.hash));
+        assert_eq!(imported, state);
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = PersistentChain::open(dir.path().to_str().unwrap()).unwrap();
+        db.store_block(&chain.chain[0], &[], &ChangeSet::default())
+            .unwrap();
+        db.store_block(&block, &built.receipts, &built.changes)
+            .unwrap();
+        assert!(matches!(
+            db.load_chain(&test_genesis(), size - 1),
+            Err(LoadError::InvalidBlock {
+                index: 1,
+                error: BlockError::TooLarge { .. }
+            })
+        ));
+
+        // Exactly at the limit is fine
+        chain.max_block_bytes = size;
+        chain.import_block(block, &mut imported).unwrap();
+        assert_eq!(chain.chain.len(), 2);
+    }
+
+    #[test]
+    fn test_wallet_key_derivation() {
+        let wallet = Wallet::from_secret_hex(VECTOR_SECRET).unwrap();
+        assert_eq!(hex::encode(wallet.address.0), VECTOR_PUBLIC_KEY);
+        assert_eq!(
+            wallet.address.to_string(),
+            "xzn16adfsqvzky9t042tlmfujeq88g8wzuhnm2nzxfd0qgdx3ac82ydq6eda3p"
+        );
+        assert_eq!(wallet.public_key(), wallet.address.public_key().unwrap());
+        assert!(Wallet::from_secret_hex("abcd").is_err());
+        let tx = signed_tx(3);
+        assert!(tx.verify_signature());
+        assert_eq!(
+            hex::encode(tx.signature.unwrap().to_bytes()),
+            "02de3e4c8510ea4b736bb270d869e8a8978009b0c6958f0c8c3a042b69fd655ae4637ffac849dc016152558356ddbd61153bfa8db0cde170f7cff00b4fe4bb05"
+        );
+    }
+
+    #[test]
+    fn test_receipts_are_committed_and_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = PersistentChain::open(dir.path().to_str().unwrap()).unwrap();
+        let state = funded_state(vector_address(), 50);
+        let tx = signed_tx(0);
+        let mut overlay = state.overlay();
+        let receipt = overlay.apply_tx(&tx).unwrap();
+        let changes = overlay.into_changes();
+
+        let mut chain = Blockchain::new();
+        let receipts = vec![receipt.clone()];
+        let block = chain.next_block(vec![tx.clone()], &receipts, 1, ZERO_HASH.into(), "p".into());
+        chain
+            .append(block, &mut state.clone(), changes.clone())
+            .unwrap();
+        let block = chain.chain.last().unwrap();
+        assert_eq!(block.header.receipts_root, Receipt::root(&receipts));
+        db.store_block(block, &receipts, &changes).unwrap();
+
+        let stored = db.get_receipt(&tx.hash()).unwrap();
+        assert_eq!(stored.block_index, 1);
+        assert_eq!(stored.position, 0);
+        assert_eq!(stored.receipt, receipt);
+        assert_eq!(db.get_block(1).unwrap().hash, block.hash);
+        assert!(db.get_receipt(&vector_tx().hash()).is_none());
+    }
+
+    // Block 0 of Blockchain::new(), which the test databases start from
+    fn test_genesis() -> Block {
+        Blockchain::new().chain.remove(0)
+    }
+
+    // Stores genesis, funded with 50 for the vector account, and two blocks
+    // of transfers; returns the open PersistentChain with the chain and state
+    // the producer ended with. The database is returned because sled releases
+    // its file lock asynchronously, so reopening the same path straight away
+    // can fail.
+    fn store_test_chain(path: &str) -> (PersistentChain, Blockchain, StateStore) {
+        let mut state = funded_state(vector_address(), 50);
+        let mut chain = Blockchain::new();
+        let db = PersistentChain::open(path).unwrap();
+        // Genesis funds are written alongside block 0
+        let genesis = ChangeSet {
+            balances: state.balances().clone(),
+            ..ChangeSet::default()
+        };
+        db.store_block(&chain.chain[0], &[], &genesis).unwrap();
+        for nonce in 0..2 {
+            let tx = signed_tx(nonce);
+            let mut overlay = state.overlay();
+            let receipts = vec![overlay.apply_tx(&tx).unwrap()];
+            let state_root = overlay.state_root();
+            let changes = overlay.into_changes();
+            let block = chain.next_block(vec![tx], &receipts, 1 + nonce, state_root, "p".into());
+            db.store_block(&block, &receipts, &changes).unwrap();
+            chain.append(block, &mut state, changes).unwrap();
+        }
+        db.db.flush().unwrap();
+        (db, chain, state)
+    }
+
+    #[test]
+    fn test_state_at_past_heights() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, chain, state) = store_test_chain(dir.path().to_str().unwrap());
+        assert_eq!(chain.state_at(&state, 2), Some(state.clone()));
+        let first = chain.state_at(&state, 1).unwrap();
+        assert_eq!(first.state_root(), chain.chain[1].header.state_root);
+        assert_eq!(
+            chain.state_at(&state, 0),
+            Some(funded_state(vector_address(), 50))
+        );
+        assert_eq!(chain.state_at(&state, 3), None);
+    }
+
+    #[test]
+    fn test_state_is_stored_with_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let (db, _, state) = store_test_chain(path);
+        let loaded = db.load_state(DEVNET_CHAIN_ID).unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.burned, 2);
+        assert_eq!(loaded.balance(&vector_address()), 6);
+        assert_eq!(loaded.total_supply(), Some(50));
+    }
+
+    #[test]
+    fn test_resume_from_stored_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let empty = PersistentChain::open(dir.path().join("empty").to_str().unwrap()).unwrap();
+        assert_eq!(empty.head().unwrap(), None);
+        assert_eq!(
+            empty
+                .load_chain(&test_genesis(), u64::MAX)
+                .unwrap()
+                .chain
+                .len(),
+            1
+        );
+
+        let (db, chain, state) = store_test_chain(path);
+        assert_eq!(db.head().unwrap(), Some(2));
+        let loaded = db.load_chain(&test_genesis(), u64::MAX).unwrap();
+        assert_eq!(loaded.latest_hash(), chain.latest_hash());
+        assert_eq!(loaded.chain.len(), 3);
+        // The next block builds on the stored head instead of overwriting it
+        let next = loaded.next_block(vec![], &[], 2, ZERO_HASH.into(), "p".into());
+        assert_eq!(next.header.index, 3);
+
+        let audited = db
+            .reexecute(&loaded, funded_state(vector_address(), 50))
+            .unwrap();
+        assert_eq!(audited, state);
+        // Re-executing from the wrong genesis state is caught
+        let mut extra = funded_state(vector_address(), 50);
+        extra.allocate(Address([0xcc; 32]), 5).unwrap();
+        assert!(matches!(
+            db.reexecute(&loaded, extra),
+            Err(LoadError::InvalidBlock {
+                index: 1,
+                error: BlockError::StateRootMismatch
+            })
+        ));
+        // The burned total is not in the state tree, only in the comparison
+        let mut burned = funded_state(vector_address(), 50);
+        burned.burned = 5;
+        assert!(matches!(
+            db.reexecute(&loaded, burned),
+            Err(LoadError::StateMismatch)
+        ));
+        assert!(matches!(
+            db.reexecute(&loaded, funded_state(vector_address(), 0)),
+            Err(LoadError::InvalidBlock {
+                index: 1,
+                error: BlockError::InvalidTransaction { position: 0, .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn test_apply_update_follows_reorgs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let (db, _, mut state) = store_test_chain(path);
+        let genesis_state = funded_state(vector_address(), 50);
+        // Reorgs unwind the chain as loaded with the undo logs read from disk
+        let mut chain = db.load_chain(&test_genesis(), u64::MAX).unwrap();
+
+        // A rival branch from block 1 that ends up one block longer
+        let mut rival = Blockchain::new();
+        let mut rival_state = genesis_state.clone();
+        let (_, changes) = rival_state
+            .execute_block(&chain.chain[1], u64::MAX)
+            .unwrap();
+        rival
+            .append(chain.chain[1].clone(), &mut rival_state, changes)
+            .unwrap();
+        let mut branch = Vec::new();
+        for nonce in 1..3 {
+            let built = rival.build_block(&rival_state, vec![signed_tx(nonce)], "q".into());
+            rival
+                .append(built.block.clone(), &mut rival_state, built.changes)
+                .unwrap();
+            branch.push(built.block);
+        }
+
+        let side = chain.import_block(branch[0].clone(), &mut state).unwrap();
+        db.apply_update(&side).unwrap();
+        assert_eq!(db.head().unwrap(), Some(2));
+        let update = chain.import_block(branch[1].clone(), &mut state).unwrap();
+        assert!(update.is_reorg());
+        db.apply_update(&update).unwrap();
+        assert_eq!(db.load_state(DEVNET_CHAIN_ID).unwrap(), state);
+        let loaded = db.load_chain(&test_genesis(), u64::MAX).unwrap();
+        assert_eq!(loaded.latest_hash(), chain.latest_hash());
+        assert_eq!(loaded.tip().header.proposer, "q");
+        assert!(db.get_receipt(&signed_tx(2).hash()).is_some());
+
+        // Plain extensions are stored the same way
+        let built = rival.build_block(&rival_state, vec![signed_tx(3)], "q".into());
+        let update = chain.import_block(built.block, &mut state).unwrap();
+        db.apply_update(&update).unwrap();
+        assert_eq!(db.head().unwrap(), Some(4));
+        assert_eq!(db.load_state(DEVNET_CHAIN_ID).unwrap(), state);
+    }
+
+    #[test]
+    fn test_revert_to_rewinds_head_and_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let (db, chain, state) = store_test_chain(path);
+        let tx_hash = chain.chain[2].transactions[0].hash();
+        assert!(db.get_receipt(&tx_hash).is_some());
+
+        // Reverting past the head changes nothing
+        db.revert_to(5).unwrap();
+        assert_eq!(db.load_state(DEVNET_CHAIN_ID).unwrap(), state);
+
+        db.revert_to(1).unwrap();
+        assert_eq!(db.head().unwrap(), Some(1));
+        assert!(db.get_receipt(&tx_hash).is_none());
+        let loaded = db.load_chain(&test_genesis(), u64::MAX).unwrap();
+        assert_eq!(loaded.latest_hash(), chain.chain[1].hash);
+        let stored = db.load_state(DEVNET_CHAIN_ID).unwrap();
+        assert_eq!(stored.state_root(), chain.chain[1].header.state_root);
+        let mut replayed = funded_state(vector_address(), 50);
+        loaded.replay(&mut replayed).unwrap();
+        assert_eq!(stored, replayed);
+        assert_eq!(stored.burned, 1);
+
+        // Genesis is always kept, with its allocation
+        db.revert_to(0).unwrap();
+        assert_eq!(db.head().unwrap(), Some(0));
+        assert_eq!(
+            db.load_state(DEVNET_CHAIN_ID).unwrap(),
+            funded_state(vector_address(), 50)
+        );
+        // The rewound database accepts a new block 1
+        let block = &chain.chain[1];
+        let mut state = funded_state(vector_address(), 50);
+        let mut overlay = state.overlay();
+        let receipts = vec![overlay.apply_tx(&block.transactions[0]).unwrap()];
+        let changes = overlay.into_changes();
+        db.store_block(block, &receipts, &changes).unwrap();
+        state.commit(changes);
+        assert_eq!(db.load_state(DEVNET_CHAIN_ID).unwrap(), state);
+    }
+
+    #[test]
+    fn test_revert_needs_undo_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let (db, _, _) = store_test_chain(path);
+        db.undo.remove(1u64.to_be_bytes()).unwrap();
+        assert!(matches!(db.revert_to(0), Err(LoadError::MissingUndo(1))));
+        // Block 2 was undone before the missing log stopped the rewind
+        assert_eq!(db.head().unwrap(), Some(1));
+        // The loaded chain keeps every log, so it cannot do without one
+        assert!(matches!(
+            db.load_chain(&test_genesis(), u64::MAX),
+            Err(LoadError::MissingUndo(1))
+        ));
+    }
+
+    #[test]
+    fn test_load_rejects_damaged_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let (db, chain, _) = store_test_chain(path);
+
+        let mut tampered = chain.chain[1].clone();
+        tampered.transactions[0].amount = 1;
+        db.db
+            .insert(1u64.to_be_bytes(), bincode::serialize(&tampered).unwrap())
+            .unwrap();
+        assert!(matches!(
+            db.load_chain(&test_genesis(), u64::MAX),
+            Err(LoadError::InvalidBlock {
+                index: 1,
+                error: BlockError::TxRootMismatch
+            })
+        ));
+
+        db.db.remove(1u64.to_be_bytes()).unwrap();
+        assert!(matches!(
+            db.load_chain(&test_genesis(), u64::MAX),
+            Err(LoadError::MissingBlock(1))
+        ));
+
+        let foreign = Block::new(0, 1, vec![], &[], "0".into(), ZERO_HASH.into(), "x".into());
+        db.db
+            .insert(0u64.to_be_bytes(), bincode::serialize(&foreign).unwrap())
+            .unwrap();
+        assert!(matches!(
+            db.load_chain(&test_genesis(), u64::MAX),
+            Err(LoadError::GenesisMismatch { .. })
+        ));
+    }
 }
@@ -0,0 +1,171 @@
+// merkle.rs
+// Binary Merkle tree over transaction hashes, with inclusion proofs.
+//
+// The tree follows RFC 6962 (Certificate Transparency):
+//
+//   MTH({})     = SHA-256()
+//   MTH({d})    = SHA-256(0x00 || d)
+//   MTH(D[n])   = SHA-256(0x01 || MTH(D[0..k]) || MTH(D[k..n]))
+//
+// where k is the largest power of two smaller than n. The leaf and node
+// prefixes keep leaves and interior nodes from being confused, and splitting
+// on powers of two means no leaf is ever duplicated to fill the tree.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Largest power of two strictly smaller than n (n > 1)
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => Sha256::digest([]).into(),
+        1 => leaf_hash(&leaves[0]),
+        n => {
+            let k = split_point(n);
+            node_hash(&merkle_root(&leaves[..k]), &merkle_root(&leaves[k..]))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub leaf_index: u64,
+    pub leaf_count: u64,
+    // Sibling hashes ordered from the leaf up to the root
+    pub path: Vec<Hash>,
+}
+
+impl MerkleProof {
+    pub fn new(leaves: &[Hash], index: usize) -> Option<Self> {
+        if index >= leaves.len() {
+            return None;
+        }
+        let mut path = Vec::new();
+        Self::audit_path(leaves, index, &mut path);
+        Some(MerkleProof {
+            leaf_index: index as u64,
+            leaf_count: leaves.len() as u64,
+            path,
+        })
+    }
+
+    fn audit_path(leaves: &[Hash], index: usize, path: &mut Vec<Hash>) {
+        if leaves.len() <= 1 {
+            return;
+        }
+        let k = split_point(leaves.len());
+        if index < k {
+            Self::audit_path(&leaves[..k], index, path);
+            path.push(merkle_root(&leaves[k..]));
+        } else {
+            Self::audit_path(&leaves[k..], index - k, path);
+            path.push(merkle_root(&leaves[..k]));
+        }
+    }
+
+    // Recomputes the root implied by this proof for `leaf`, or None if the
+    // proof is malformed for its claimed position (RFC 9162, 2.1.3.2)
+    pub fn compute_root(&self, leaf: &Hash) -> Option<Hash> {
+        if self.leaf_index >= self.leaf_count {
+            return None;
+        }
+        let mut fnode = self.leaf_index;
+        let mut snode = self.leaf_count - 1;
+        let mut root = leaf_hash(leaf);
+        for sibling in &self.path {
+            if snode == 0 {
+                return None;
+            }
+            if fnode & 1 == 1 || fnode == snode {
+                root = node_hash(sibling, &root);
+                while fnode & 1 == 0 && fnode != 0 {
+                    fnode >>= 1;
+                    snode >>= 1;
+                }
+            } else {
+                root = node_hash(&root, sibling);
+            }
+            fnode >>= 1;
+            snode >>= 1;
+        }
+        (snode == 0).then_some(root)
+    }
+
+    pub fn verify(&self, root: &Hash, leaf: &Hash) -> bool {
+        self.compute_root(leaf).as_ref() == Some(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<Hash> {
+        (0..n).map(|i| Sha256::digest([i as u8]).into()).collect()
+    }
+
+    #[test]
+    fn test_root_shapes() {
+        let l = leaves(3);
+        assert_eq!(merkle_root(&[]), <Hash>::from(Sha256::digest([])));
+        assert_eq!(merkle_root(&l[..1]), leaf_hash(&l[0]));
+        let left = node_hash(&leaf_hash(&l[0]), &leaf_hash(&l[1]));
+        assert_eq!(merkle_root(&l), node_hash(&left, &leaf_hash(&l[2])));
+    }
+
+    #[test]
+    fn test_proofs_verify_for_every_leaf() {
+        for n in 1..=17 {
+            let l = leaves(n);
+            let root = merkle_root(&l);
+            for (i, leaf) in l.iter().enumerate() {
+                let proof = MerkleProof::new(&l, i).unwrap();
+                assert!(proof.verify(&root, leaf), "n={n} i={i}");
+            }
+            assert!(MerkleProof::new(&l, n).is_none());
+        }
+    }
+
+    #[test]
+    fn test_proof_rejects_wrong_leaf_or_position() {
+        let l = leaves(6);
+        let root = merkle_root(&l);
+        let proof = MerkleProof::new(&l, 2).unwrap();
+        assert!(!proof.verify(&root, &l[3]));
+
+        let mut moved = proof.clone();
+        moved.leaf_index = 3;
+        assert!(!moved.verify(&root, &l[2]));
+
+        let mut truncated = proof;
+        truncated.path.pop();
+        assert!(!truncated.verify(&root, &l[2]));
+    }
+}
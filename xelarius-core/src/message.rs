@@ -0,0 +1,120 @@
+// message.rs
+// What nodes gossip to each other. Every gossipsub message is one
+// NetworkMessage, bincode-encoded like stored blocks, on the topic for its
+// kind; anything that does not decode, is on the wrong topic or is larger
+// than MAX_MESSAGE_BYTES is rejected by the receiver.
+
+use crate::{Block, Transaction};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+// Room for a full block at the devnet's 1 MiB max_block_bytes, plus headers
+pub const MAX_MESSAGE_BYTES: usize = 2 << 20;
+// A transaction encodes to no more bytes here than in its canonical
+// encoding, so a block fits in a message when its transactions leave this
+// much room for the header, hash and framing. GenesisConfig::validate holds
+// max_block_bytes to it.
+pub const BLOCK_ENVELOPE_BYTES: usize = 4 << 10;
+
+pub const BLOCK_TOPIC: &str = "xelarius-blocks";
+pub const TX_TOPIC: &str = "xelarius-txs";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NetworkMessage {
+    // A block the sender produced or imported
+    NewBlock(Block),
+    // A transaction the sender admitted to its mempool
+    NewTx(Transaction),
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_MESSAGE_BYTES as u64)
+}
+
+impl NetworkMessage {
+    // Fails for a message larger than MAX_MESSAGE_BYTES
+    pub fn encode(&self) -> Result<Vec<u8>, bincode::Error> {
+        options().serialize(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        options().deserialize(bytes)
+    }
+
+    pub fn topic(&self) -> &'static str {
+        match self {
+            NetworkMessage::NewBlock(_) => BLOCK_TOPIC,
+            NetworkMessage::NewTx(_) => TX_TOPIC,
+        }
+    }
+
+    // For logs
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NetworkMessage::NewBlock(_) => "block",
+            NetworkMessage::NewTx(_) => "transaction",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, Blockchain, Wallet};
+
+    #[test]
+    fn test_messages_round_trip() {
+        let wallet = Wallet::from_secret_key(&[7; 32]);
+        let mut tx = Transaction {
+            chain_id: "xelarius-devnet".into(),
+            from: wallet.address,
+            to: Address([0xbb; 32]),
+            amount: 1,
+            fee: 1,
+            nonce: 0,
+            signature: None,
+        };
+        wallet.sign_transaction(&mut tx);
+        let encoded = NetworkMessage::NewTx(tx.clone()).encode().unwrap();
+        // What makes BLOCK_ENVELOPE_BYTES enough
+        assert!(encoded.len() - 4 <= tx.canonical_bytes().len());
+        let decoded = NetworkMessage::decode(&encoded);
+        assert!(matches!(decoded, Ok(NetworkMessage::NewTx(got)) if got == tx));
+        assert_eq!(NetworkMessage::NewTx(tx.clone()).topic(), TX_TOPIC);
+
+        let chain = Blockchain::new();
+        let block = chain.next_block(vec![tx], &[], 1, "root".into(), "p".into());
+        let decoded =
+            NetworkMessage::decode(&NetworkMessage::NewBlock(block.clone()).encode().unwrap());
+        assert!(matches!(decoded, Ok(NetworkMessage::NewBlock(got))
+            if got.hash == block.hash && got.transactions == block.transactions));
+        assert_eq!(NetworkMessage::NewBlock(block).topic(), BLOCK_TOPIC);
+    }
+
+    #[test]
+    fn test_rejects_malformed_messages() {
+        let chain = Blockchain::new();
+        let encoded = NetworkMessage::NewBlock(chain.tip().clone())
+            .encode()
+            .unwrap();
+        assert!(NetworkMessage::decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(NetworkMessage::decode(&trailing).is_err());
+        assert!(NetworkMessage::decode(&[9, 0, 0, 0]).is_err());
+        // A previous_hash length far beyond the input fails without being
+        // allocated: variant, index and timestamp come first
+        let mut huge = encoded[..20].to_vec();
+        huge.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(NetworkMessage::decode(&huge).is_err());
+    }
+
+    #[test]
+    fn test_oversized_messages_do_not_encode() {
+        let mut block = Blockchain::new().tip().clone();
+        block.header.proposer = "p".repeat(MAX_MESSAGE_BYTES);
+        assert!(NetworkMessage::NewBlock(block).encode().is_err());
+    }
+}
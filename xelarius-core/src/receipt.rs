@@ -0,0 +1,86 @@
+// receipt.rs
+// Execution receipts recorded for every transaction included in a block
+
+use crate::encoding::{Encoder, TAG_EVENT, TAG_RECEIPT, sha256, to_hex};
+use crate::merkle::{Hash, merkle_root};
+use crate::{Address, TxHash};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TxStatus {
+    Success,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Event {
+    Transfer {
+        from: Address,
+        to: Address,
+        amount: u64,
+    },
+    FeeBurned {
+        from: Address,
+        amount: u64,
+    },
+}
+
+impl Event {
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut enc = Encoder::new(TAG_EVENT);
+        match self {
+            Event::Transfer { from, to, amount } => {
+                enc.u8(0).bytes(&from.0).bytes(&to.0).u64(*amount)
+            }
+            Event::FeeBurned { from, amount } => enc.u8(1).bytes(&from.0).u64(*amount),
+        };
+        enc.finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Receipt {
+    pub tx_hash: TxHash,
+    pub status: TxStatus,
+    pub failure_reason: Option<String>,
+    pub fee_paid: u64,
+    pub events: Vec<Event>,
+}
+
+impl Receipt {
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let status = match self.status {
+            TxStatus::Success => 0,
+            TxStatus::Failed => 1,
+        };
+        Encoder::new(TAG_RECEIPT)
+            .bytes(&self.tx_hash.0)
+            .u8(status)
+            .option(self.failure_reason.as_deref(), |e, reason| {
+                e.str(reason);
+            })
+            .u64(self.fee_paid)
+            .list(&self.events, |e, event| {
+                e.bytes(&event.canonical_bytes());
+            })
+            .finish()
+    }
+
+    pub fn leaf_hash(&self) -> Hash {
+        sha256(&self.canonical_bytes())
+    }
+
+    // Root committed to by BlockHeader::receipts_root
+    pub fn root(receipts: &[Receipt]) -> String {
+        let leaves: Vec<Hash> = receipts.iter().map(Receipt::leaf_hash).collect();
+        to_hex(&merkle_root(&leaves))
+    }
+}
+
+// A receipt together with where its transaction landed on chain
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexedReceipt {
+    pub block_index: u64,
+    pub position: u32,
+    pub receipt: Receipt,
+}
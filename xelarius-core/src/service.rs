@@ -0,0 +1,144 @@
+// service.rs
+// What a node does with its chain, state, mempool and database: produce a
+// block, or import one from a peer, keeping the database in step with
+// memory. The node binary and the simulation in sim.rs both go through
+// these, so a simulated run exercises the node's own producer and import
+// path.
+
+use crate::error::ImportError;
+use crate::{
+    Block, Blockchain, BuiltBlock, ChainUpdate, Mempool, PersistentChain, StateStore, Transaction,
+    TxError,
+};
+
+// What one turn of the producer did
+#[derive(Debug)]
+pub struct Production {
+    // Waited longer than the mempool's max_tx_age
+    pub expired: Vec<Transaction>,
+    // Drained from the mempool but left out of the block because they
+    // cannot be applied
+    pub dropped: Vec<(Transaction, TxError)>,
+    // The block built and whether it was stored and appended; None if the
+    // mempool had nothing to include
+    pub block: Option<(Block, Result<(), ImportError>)>,
+}
+
+// Drops expired transactions, then builds a block on the tip from as many
+// mempool transactions as fit in max_block_bytes. The block is stored
+// before it is appended, so state only changes once it is on disk; if it
+// is rejected or cannot be stored, its state changes are discarded.
+pub fn produce_once(
+    chain: &mut Blockchain,
+    state: &mut StateStore,
+    mempool: &Mempool,
+    db: &PersistentChain,
+    proposer: &str,
+) -> Production {
+    let expired = mempool.expire();
+    let txs = mempool.drain_block(mempool.max_block_bytes);
+    if txs.is_empty() {
+        return Production {
+            expired,
+            dropped: Vec::new(),
+            block: None,
+        };
+    }
+    let BuiltBlock {
+        block,
+        receipts,
+        changes,
+        dropped,
+    } = chain.build_block(state, txs, proposer.to_string());
+    // Checked as append will check it, so a block append would refuse is
+    // never written
+    let stored = chain
+        .check_extends_tip(&block)
+        .map_err(ImportError::Invalid)
+        .and_then(|()| {
+            db.store_block(&block, &receipts, &changes)
+                .map_err(|e| ImportError::Store(e.into()))
+        })
+        .and_then(|()| {
+            chain
+                .append(block.clone(), state, changes)
+                .map_err(ImportError::Invalid)
+        });
+    chain.prune();
+    Production {
+        expired,
+        dropped,
+        block: Some((block, stored)),
+    }
+}
+
+// Checks the block against its parent, lets fork choice decide whether it
+// becomes the tip, and writes any resulting change to the database. If that
+// write fails the import is rolled back, so memory keeps matching disk;
+// otherwise side branches that fell too far behind are pruned.
+pub fn import_block(
+    chain: &mut Blockchain,
+    state: &mut StateStore,
+    db: &PersistentChain,
+    block: Block,
+) -> Result<ChainUpdate, ImportError> {
+    let update = chain
+        .import_block(block, state)
+        .map_err(ImportError::Invalid)?;
+    if let Err(e) = db.apply_update(&update) {
+        chain.rollback(&update, state);
+        return Err(ImportError::Store(e));
+    }
+    chain.prune();
+    Ok(update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::DEVNET_CHAIN_ID;
+    use crate::{BlockError, ChangeSet, Wallet};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_block_the_chain_refuses_is_not_stored() {
+        let wallet = Wallet::generate();
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.commit(ChangeSet {
+            balances: HashMap::from([(wallet.address, 50)]),
+            ..ChangeSet::default()
+        });
+        let mut chain = Blockchain::new();
+        let db = PersistentChain::temporary().unwrap();
+        db.store_block(&chain.chain[0], &[], &ChangeSet::default())
+            .unwrap();
+        let mempool = Mempool::new(DEVNET_CHAIN_ID);
+        let mut tx = Transaction {
+            chain_id: DEVNET_CHAIN_ID.into(),
+            from: wallet.address,
+            to: wallet.address,
+            amount: 1,
+            fee: 0,
+            nonce: 0,
+            signature: None,
+        };
+        wallet.sign_transaction(&mut tx);
+        mempool.add_tx(tx).unwrap();
+        // The mempool's limit and the chain's are set separately
+        chain.max_block_bytes = 1;
+        let before = state.clone();
+
+        let production = produce_once(&mut chain, &mut state, &mempool, &db, "p");
+        let (_, stored) = production.block.unwrap();
+        assert!(matches!(
+            stored,
+            Err(ImportError::Invalid(BlockError::TooLarge {
+                maximum: 1,
+                ..
+            }))
+        ));
+        assert_eq!(db.head().unwrap(), Some(0));
+        assert_eq!(chain.chain.len(), 1);
+        assert_eq!(state, before);
+    }
+}
@@ -0,0 +1,476 @@
+// sim.rs
+// Deterministic simulation of several nodes in one process. Time is virtual:
+// it jumps from one scheduled event to the next, and every node reads it
+// through its own ManualClock. Blocks travel between nodes over a simulated
+// gossip network with random latency, and every random choice comes from a
+// single seed, so a run replays exactly, timing-dependent bugs included.
+//
+// Nodes run the node binary's own steps from service.rs against a temporary
+// database: every block time, each node calls produce_once and gossips the
+// block it stored; blocks from peers go through service::import_block.
+
+use crate::clock::ManualClock;
+use crate::error::SimError;
+use crate::genesis::GenesisConfig;
+use crate::service::{self, Production};
+use crate::{Block, BlockError, Blockchain, ChangeSet, ImportError, LongestChain, Mempool};
+use crate::{PersistentChain, StateStore, Transaction, TxError, TxHash};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimConfig {
+    pub nodes: usize,
+    pub seed: u64,
+    // Each block takes between these many milliseconds to reach a peer
+    pub min_latency_millis: u64,
+    pub max_latency_millis: u64,
+    // Each node's clock runs a random amount up to this far ahead
+    pub max_clock_skew_millis: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            nodes: 3,
+            seed: 0,
+            min_latency_millis: 50,
+            max_latency_millis: 500,
+            max_clock_skew_millis: 1_000,
+        }
+    }
+}
+
+// What happened during a run, in order. Two runs from the same seed and
+// inputs produce the same trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimEvent {
+    Produced {
+        at: u64,
+        node: usize,
+        index: u64,
+        hash: String,
+    },
+    // `applied` is empty for a block stored on a side branch
+    Imported {
+        at: u64,
+        node: usize,
+        hash: String,
+        reverted: usize,
+        applied: usize,
+    },
+    Rejected {
+        at: u64,
+        node: usize,
+        hash: String,
+        error: BlockError,
+    },
+    // Valid, but the node's database would not take it
+    StoreFailed {
+        at: u64,
+        node: usize,
+        hash: String,
+        error: String,
+    },
+    TxRejected {
+        at: u64,
+        node: usize,
+        tx: TxHash,
+        error: TxError,
+    },
+    TxDropped {
+        at: u64,
+        node: usize,
+        tx: TxHash,
+        error: TxError,
+    },
+}
+
+pub struct SimNode {
+    pub chain: Blockchain,
+    pub state: StateStore,
+    pub mempool: Mempool,
+    pub db: PersistentChain,
+    pub clock: Arc<ManualClock>,
+    // How far this node's clock is ahead of virtual time, in milliseconds
+    pub skew_millis: u64,
+}
+
+#[derive(Debug)]
+enum Event {
+    Produce { node: usize },
+    Deliver { node: usize, block: Block },
+    Submit { node: usize, tx: Transaction },
+}
+
+// Ordered by time, then by when it was scheduled, so simultaneous events
+// always run in the same order
+#[derive(Debug)]
+struct Scheduled {
+    at: u64,
+    seq: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+pub struct Simulation {
+    pub genesis: GenesisConfig,
+    pub config: SimConfig,
+    pub nodes: Vec<SimNode>,
+    pub trace: Vec<SimEvent>,
+    // Virtual unix milliseconds
+    now: u64,
+    rng: StdRng,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+}
+
+impl Simulation {
+    // Starts every node at genesis, with virtual time at the genesis time.
+    // Each node produces its first block at a random point in the first
+    // block interval.
+    pub fn new(genesis: GenesisConfig, config: SimConfig) -> Result<Self, SimError> {
+        let (genesis_block, genesis_state) = genesis.build()?;
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let now = genesis.genesis_time.saturating_mul(1000);
+        let nodes = (0..config.nodes)
+            .map(|_| {
+                let skew_millis = rng.gen_range(0..=config.max_clock_skew_millis);
+                let clock = Arc::new(ManualClock::default());
+                clock.set_millis(now + skew_millis);
+                let mut chain = Blockchain::from_genesis(genesis_block.clone(), LongestChain);
+                chain.clock = clock.clone();
+                chain.max_block_bytes = genesis.params.max_block_bytes;
+                let mut mempool = Mempool::new(&genesis.chain_id);
+                mempool.min_fee = genesis.params.min_fee;
+                mempool.max_block_bytes = genesis.params.max_block_bytes;
+                mempool.clock = clock.clone();
+                let db = PersistentChain::temporary()?;
+                let allocations = ChangeSet {
+                    balances: genesis_state.balances().clone(),
+                    ..ChangeSet::default()
+                };
+                db.store_block(&genesis_block, &[], &allocations)?;
+                Ok(SimNode {
+                    chain,
+                    state: genesis_state.clone(),
+                    mempool,
+                    db,
+                    clock,
+                    skew_millis,
+                })
+            })
+            .collect::<sled::Result<_>>()?;
+        let mut sim = Simulation {
+            genesis,
+            config,
+            nodes,
+            trace: Vec::new(),
+            now,
+            rng,
+            queue: BinaryHeap::new(),
+            seq: 0,
+        };
+        for node in 0..sim.nodes.len() {
+            let phase = sim.rng.gen_range(0..sim.block_time_millis());
+            sim.schedule(phase, Event::Produce { node });
+        }
+        Ok(sim)
+    }
+
+    pub fn now_millis(&self) -> u64 {
+        self.now
+    }
+
+    // Overrides a node's random clock skew
+    pub fn set_clock_skew(&mut self, node: usize, skew_millis: u64) {
+        self.nodes[node].skew_millis = skew_millis;
+        self.set_time(self.now);
+    }
+
+    // Hands `tx` to `node`'s mempool after `delay_millis`, as submit_tx would
+    pub fn submit_tx(&mut self, node: usize, tx: Transaction, delay_millis: u64) {
+        self.schedule(delay_millis, Event::Submit { node, tx });
+    }
+
+    // Runs every event due in the next `millis` of virtual time
+    pub fn run_for(&mut self, millis: u64) {
+        let end = self.now.saturating_add(millis);
+        while let Some(Reverse(next)) = self.queue.peek() {
+            if next.at > end {
+                break;
+            }
+            let Reverse(Scheduled { at, event, .. }) = self.queue.pop().unwrap();
+            self.set_time(at);
+            match event {
+                Event::Produce { node } => self.produce(node),
+                Event::Deliver { node, block } => self.deliver(node, block),
+                Event::Submit { node, tx } => self.submit(node, tx),
+            }
+        }
+        self.set_time(end);
+    }
+
+    pub fn tips(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .map(|node| node.chain.latest_hash())
+            .collect()
+    }
+
+    // Whether every node has the same tip
+    pub fn converged(&self) -> bool {
+        self.tips().windows(2).all(|pair| pair[0] == pair[1])
+    }
+
+    fn block_time_millis(&self) -> u64 {
+        self.genesis.params.block_time_secs.saturating_mul(1000)
+    }
+
+    fn schedule(&mut self, delay_millis: u64, event: Event) {
+        self.seq += 1;
+        self.queue.push(Reverse(Scheduled {
+            at: self.now.saturating_add(delay_millis),
+            seq: self.seq,
+            event,
+        }));
+    }
+
+    fn set_time(&mut self, now: u64) {
+        self.now = now;
+        for node in &self.nodes {
+            node.clock.set_millis(now.saturating_add(node.skew_millis));
+        }
+    }
+
+    fn produce(&mut self, index: usize) {
+        let at = self.now;
+        let node = &mut self.nodes[index];
+        let Production { dropped, block, .. } = service::produce_once(
+            &mut node.chain,
+            &mut node.state,
+            &node.mempool,
+            &node.db,
+            &format!("node-{}", index),
+        );
+        for (tx, error) in dropped {
+            self.trace.push(SimEvent::TxDropped {
+                at,
+                node: index,
+                tx: tx.hash(),
+                error,
+            });
+        }
+        match block {
+            Some((block, Ok(()))) => {
+                self.trace.push(SimEvent::Produced {
+                    at,
+                    node: index,
+                    index: block.header.index,
+                    hash: block.hash.clone(),
+                });
+                for peer in (0..self.nodes.len()).filter(|&peer| peer != index) {
+                    let latency = self
+                        .rng
+                        .gen_range(self.config.min_latency_millis..=self.config.max_latency_millis);
+                    self.schedule(
+                        latency,
+                        Event::Deliver {
+                            node: peer,
+                            block: block.clone(),
+                        },
+                    );
+                }
+            }
+            Some((block, Err(error))) => self.reject(index, block.hash, error),
+            None => {}
+        }
+        self.schedule(self.block_time_millis(), Event::Produce { node: index });
+    }
+
+    fn deliver(&mut self, index: usize, block: Block) {
+        let at = self.now;
+        let hash = block.hash.clone();
+        let node = &mut self.nodes[index];
+        match service::import_block(&mut node.chain, &mut node.state, &node.db, block) {
+            Ok(update) => self.trace.push(SimEvent::Imported {
+                at,
+                node: index,
+                hash,
+                reverted: update.reverted.len(),
+                applied: update.applied.len(),
+            }),
+            Err(error) => self.reject(index, hash, error),
+        }
+    }
+
+    fn reject(&mut self, index: usize, hash: String, error: ImportError) {
+        let at = self.now;
+        self.trace.push(match error {
+            ImportError::Invalid(error) => SimEvent::Rejected {
+                at,
+                node: index,
+                hash,
+                error,
+            },
+            ImportError::Store(error) => SimEvent::StoreFailed {
+                at,
+                node: index,
+                hash,
+                error: error.to_string(),
+            },
+        });
+    }
+
+    fn submit(&mut self, index: usize, tx: Transaction) {
+        let hash = tx.hash();
+        if let Err(error) = self.nodes[index].mempool.add_tx(tx) {
+            self.trace.push(SimEvent::TxRejected {
+                at: self.now,
+                node: index,
+                tx: hash,
+                error,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::{Allocation, COIN};
+    use crate::{Address, MAX_FUTURE_DRIFT, Wallet};
+
+    fn wallet(seed: u8) -> Wallet {
+        Wallet::from_secret_key(&[seed; 32])
+    }
+
+    // Devnet parameters, with one funded sender per seed in 1..=senders
+    fn genesis(senders: u8) -> GenesisConfig {
+        let mut genesis = GenesisConfig::devnet();
+        genesis.allocations = (1..=senders)
+            .map(|seed| Allocation {
+                address: wallet(seed).address,
+                amount: 1_000 * COIN,
+            })
+            .collect();
+        genesis
+    }
+
+    fn transfer(from: u8, nonce: u64) -> Transaction {
+        let sender = wallet(from);
+        let mut tx = Transaction {
+            chain_id: GenesisConfig::devnet().chain_id,
+            from: sender.address,
+            to: Address([0xbb; 32]),
+            amount: COIN,
+            fee: 1,
+            nonce,
+            signature: None,
+        };
+        sender.sign_transaction(&mut tx);
+        tx
+    }
+
+    // Every node gets a stream of transactions from its own sender, so its
+    // blocks race the others'. One more sender is funded but left unused.
+    fn busy_run(seed: u64, max_latency_millis: u64) -> Simulation {
+        let config = SimConfig {
+            seed,
+            max_latency_millis,
+            ..SimConfig::default()
+        };
+        let mut sim = Simulation::new(genesis(config.nodes as u8 + 1), config).unwrap();
+        for node in 0..sim.nodes.len() {
+            for nonce in 0..10 {
+                sim.submit_tx(node, transfer(node as u8 + 1, nonce), nonce * 2_000);
+            }
+        }
+        sim.run_for(60_000);
+        sim
+    }
+
+    #[test]
+    fn test_same_seed_replays_exactly() {
+        // Latency close to the block time makes nodes build on stale tips
+        let first = busy_run(7, 4_000);
+        assert!(
+            first
+                .trace
+                .iter()
+                .any(|event| matches!(event, SimEvent::Imported { reverted: 1.., .. }))
+        );
+        let second = busy_run(7, 4_000);
+        assert_eq!(first.trace, second.trace);
+        assert_eq!(first.tips(), second.tips());
+        assert_ne!(busy_run(8, 4_000).trace, first.trace);
+    }
+
+    #[test]
+    fn test_nodes_converge() {
+        let mut sim = busy_run(1, SimConfig::default().max_latency_millis);
+        // Once the senders are done, one more block settles any tie between
+        // tips of the same height. The unused sender's first transaction is
+        // valid on every branch.
+        let unused = sim.nodes.len() as u8 + 1;
+        sim.submit_tx(0, transfer(unused, 0), 0);
+        sim.run_for(30_000);
+        assert!(sim.converged());
+        let root = sim.nodes[0].state.state_root();
+        assert!(sim.nodes.iter().all(|node| node.state.state_root() == root));
+        assert_eq!(sim.nodes[0].chain.tip().header.proposer, "node-0");
+        // Produced and imported blocks, reorgs included, reached each node's
+        // database
+        for node in &sim.nodes {
+            assert_eq!(node.db.head().unwrap(), Some(node.chain.tip().header.index));
+            assert_eq!(
+                node.db.load_state(&sim.genesis.chain_id).unwrap(),
+                node.state
+            );
+        }
+    }
+
+    #[test]
+    fn test_clock_far_ahead_gets_blocks_rejected() {
+        let config = SimConfig {
+            nodes: 2,
+            max_clock_skew_millis: 0,
+            ..SimConfig::default()
+        };
+        let mut sim = Simulation::new(genesis(1), config).unwrap();
+        sim.set_clock_skew(0, (MAX_FUTURE_DRIFT + 5) * 1000);
+        sim.submit_tx(0, transfer(1, 0), 0);
+        sim.run_for(10_000);
+        assert!(sim.trace.iter().any(|event| matches!(
+            event,
+            SimEvent::Rejected {
+                node: 1,
+                error: BlockError::TimestampTooFarAhead { .. },
+                ..
+            }
+        )));
+        assert_eq!(sim.nodes[1].chain.chain.len(), 1);
+    }
+}
@@ -0,0 +1,384 @@
+// smt.rs
+// Sparse Merkle tree over 256-bit keys, used to commit to account state.
+//
+// A key's bits, most significant first, give its path from the root (0 is
+// left). Leaves hash as leaf_hash(key || value) and interior nodes as
+// node_hash(left, right), with the prefixes from merkle.rs. An empty subtree
+// hashes to 32 zero bytes, and a subtree holding a single leaf is replaced by
+// that leaf, so a path only goes as deep as needed to tell its key apart from
+// its neighbours.
+
+use crate::merkle::{Hash, leaf_hash, node_hash};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+pub const EMPTY_ROOT: Hash = [0; 32];
+
+fn bit(key: &Hash, depth: usize) -> bool {
+    key[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+fn leaf_node(key: &Hash, value: &Hash) -> Hash {
+    let mut data = [0u8; 64];
+    data[..32].copy_from_slice(key);
+    data[32..].copy_from_slice(value);
+    leaf_hash(&data)
+}
+
+// A subtree in the shape described above. Branches hold at least two leaves
+// and keep their hash, so an update only rehashes the path to its leaf.
+// Children are shared, so cloning a tree is cheap and an updated copy only
+// owns the nodes on the changed paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+enum Node {
+    #[default]
+    Empty,
+    Leaf {
+        key: Hash,
+        value: Hash,
+    },
+    Branch {
+        hash: Hash,
+        left: Arc<Node>,
+        right: Arc<Node>,
+    },
+}
+
+impl Node {
+    fn hash(&self) -> Hash {
+        match self {
+            Node::Empty => EMPTY_ROOT,
+            Node::Leaf { key, value } => leaf_node(key, value),
+            Node::Branch { hash, .. } => *hash,
+        }
+    }
+
+    // Joins two subtrees one level down, collapsing a lone leaf into its
+    // parent's place
+    fn branch(left: Arc<Node>, right: Arc<Node>) -> Node {
+        match (&*left, &*right) {
+            (Node::Empty, Node::Empty) => Node::Empty,
+            (Node::Leaf { .. }, Node::Empty) => (*left).clone(),
+            (Node::Empty, Node::Leaf { .. }) => (*right).clone(),
+            _ => Node::Branch {
+                hash: node_hash(&left.hash(), &right.hash()),
+                left,
+                right,
+            },
+        }
+    }
+
+    // The subtree at `depth` holding two leaves with different keys that
+    // share their first `depth` bits
+    fn split(a: Node, a_key: &Hash, b: Node, b_key: &Hash, depth: usize) -> Node {
+        let (a_bit, b_bit) = (bit(a_key, depth), bit(b_key, depth));
+        if a_bit != b_bit {
+            let (left, right) = if a_bit { (b, a) } else { (a, b) };
+            return Node::branch(Arc::new(left), Arc::new(right));
+        }
+        let inner = Arc::new(Node::split(a, a_key, b, b_key, depth + 1));
+        let empty = Arc::new(Node::Empty);
+        if a_bit {
+            Node::branch(empty, inner)
+        } else {
+            Node::branch(inner, empty)
+        }
+    }
+
+    fn insert(node: &Arc<Node>, key: Hash, value: Hash, depth: usize) -> Node {
+        match &**node {
+            Node::Empty => Node::Leaf { key, value },
+            Node::Leaf { key: other, .. } if *other == key => Node::Leaf { key, value },
+            Node::Leaf { key: other, .. } => {
+                let other = *other;
+                Node::split(
+                    (**node).clone(),
+                    &other,
+                    Node::Leaf { key, value },
+                    &key,
+                    depth,
+                )
+            }
+            Node::Branch { left, right, .. } => {
+                if bit(&key, depth) {
+                    let right = Node::insert(right, key, value, depth + 1);
+                    Node::branch(left.clone(), Arc::new(right))
+                } else {
+                    let left = Node::insert(left, key, value, depth + 1);
+                    Node::branch(Arc::new(left), right.clone())
+                }
+            }
+        }
+    }
+
+    fn remove(node: &Arc<Node>, key: &Hash, depth: usize) -> Node {
+        match &**node {
+            Node::Leaf { key: other, .. } if other == key => Node::Empty,
+            Node::Empty | Node::Leaf { .. } => (**node).clone(),
+            Node::Branch { left, right, .. } => {
+                if bit(key, depth) {
+                    let right = Node::remove(right, key, depth + 1);
+                    Node::branch(left.clone(), Arc::new(right))
+                } else {
+                    let left = Node::remove(left, key, depth + 1);
+                    Node::branch(Arc::new(left), right.clone())
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SparseMerkleTree {
+    root: Arc<Node>,
+}
+
+// A whole tree is too much to print; its root identifies it
+impl fmt::Debug for SparseMerkleTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SparseMerkleTree")
+            .field("root", &crate::encoding::to_hex(&self.root()))
+            .finish()
+    }
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        SparseMerkleTree::default()
+    }
+
+    pub fn insert(&mut self, key: Hash, value: Hash) {
+        self.root = Arc::new(Node::insert(&self.root, key, value, 0));
+    }
+
+    pub fn remove(&mut self, key: &Hash) {
+        self.root = Arc::new(Node::remove(&self.root, key, 0));
+    }
+
+    pub fn get(&self, key: &Hash) -> Option<&Hash> {
+        let mut node = &*self.root;
+        let mut depth = 0;
+        loop {
+            match node {
+                Node::Empty => return None,
+                Node::Leaf { key: k, value } => return (k == key).then_some(value),
+                Node::Branch { left, right, .. } => {
+                    node = if bit(key, depth) { right } else { left };
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.root.hash()
+    }
+
+    // Proof of the value under `key`, or of its absence
+    pub fn prove(&self, key: &Hash) -> SparseMerkleProof {
+        let mut node = &*self.root;
+        let mut siblings = Vec::new();
+        let mut depth = 0;
+        let leaf = loop {
+            match node {
+                Node::Empty => break None,
+                Node::Leaf { key, value } => break Some((*key, *value)),
+                Node::Branch { left, right, .. } => {
+                    if bit(key, depth) {
+                        siblings.push(left.hash());
+                        node = right;
+                    } else {
+                        siblings.push(right.hash());
+                        node = left;
+                    }
+                    depth += 1;
+                }
+            }
+        };
+        siblings.reverse();
+        SparseMerkleProof { leaf, siblings }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseMerkleProof {
+    // Whatever sits where the key's path ends: the key's own leaf, another
+    // leaf sharing the path so far, or None for an empty subtree
+    pub leaf: Option<(Hash, Hash)>,
+    // Sibling hashes ordered from the leaf up to the root
+    pub siblings: Vec<Hash>,
+}
+
+impl SparseMerkleProof {
+    // Checks that `key` maps to `value` under `root`, or that it is absent
+    // when `value` is None
+    pub fn verify(&self, root: &Hash, key: &Hash, value: Option<&Hash>) -> bool {
+        let depth = self.siblings.len();
+        if depth >= 256 {
+            return false;
+        }
+        let on_path = |k: &Hash| (0..depth).all(|d| bit(k, d) == bit(key, d));
+        match (&self.leaf, value) {
+            (Some((k, v)), Some(value)) if k == key && v == value => {}
+            // Absent: the path ends in another key's leaf, which must really
+            // lie on this key's path
+            (Some((k, _)), None) if k != key && on_path(k) => {}
+            (None, None) => {}
+            _ => return false,
+        }
+        let mut current = match &self.leaf {
+            Some((k, v)) => leaf_node(k, v),
+            None => EMPTY_ROOT,
+        };
+        for (i, sibling) in self.siblings.iter().enumerate() {
+            current = if bit(key, depth - 1 - i) {
+                node_hash(sibling, &current)
+            } else {
+                node_hash(&current, sibling)
+            };
+        }
+        current == *root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn key(i: u32) -> Hash {
+        Sha256::digest(i.to_be_bytes()).into()
+    }
+
+    fn tree(n: u32) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for i in 0..n {
+            tree.insert(key(i), key(i + 1000));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_root_shapes() {
+        assert_eq!(SparseMerkleTree::new().root(), EMPTY_ROOT);
+        let one = tree(1);
+        assert_eq!(one.root(), leaf_node(&key(0), &key(1000)));
+
+        let mut left = [0u8; 32];
+        let mut right = [0u8; 32];
+        right[0] = 0x80;
+        let mut two = SparseMerkleTree::new();
+        two.insert(right, key(1));
+        two.insert(left, key(0));
+        assert_eq!(
+            two.root(),
+            node_hash(&leaf_node(&left, &key(0)), &leaf_node(&right, &key(1)))
+        );
+        // Sharing the first bit pushes both leaves one level down, next to
+        // an empty subtree
+        left[0] = 0x80;
+        right[0] = 0xc0;
+        let mut deep = SparseMerkleTree::new();
+        deep.insert(left, key(0));
+        deep.insert(right, key(1));
+        let inner = node_hash(&leaf_node(&left, &key(0)), &leaf_node(&right, &key(1)));
+        assert_eq!(deep.root(), node_hash(&EMPTY_ROOT, &inner));
+    }
+
+    #[test]
+    fn test_root_ignores_insertion_order() {
+        let mut reversed = SparseMerkleTree::new();
+        for i in (0..20).rev() {
+            reversed.insert(key(i), key(i + 1000));
+        }
+        assert_eq!(reversed.root(), tree(20).root());
+        let mut changed = tree(20);
+        changed.insert(key(7), key(7));
+        assert_ne!(changed.root(), tree(20).root());
+        changed.insert(key(7), key(1007));
+        changed.insert(key(99), key(99));
+        changed.remove(&key(99));
+        assert_eq!(changed.root(), tree(20).root());
+    }
+
+    // The root straight from the shape rules, for checking the updated one
+    fn reference_root(leaves: &[(Hash, Hash)], depth: usize) -> Hash {
+        match leaves {
+            [] => EMPTY_ROOT,
+            [(key, value)] => leaf_node(key, value),
+            _ => {
+                let split = leaves.partition_point(|(key, _)| !bit(key, depth));
+                node_hash(
+                    &reference_root(&leaves[..split], depth + 1),
+                    &reference_root(&leaves[split..], depth + 1),
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn test_updates_match_a_tree_built_from_scratch() {
+        let mut tree = SparseMerkleTree::new();
+        let mut leaves = std::collections::BTreeMap::new();
+        for step in 0..200u32 {
+            let k = key(step * 7 % 31);
+            if step % 3 == 2 {
+                tree.remove(&k);
+                leaves.remove(&k);
+            } else {
+                tree.insert(k, key(step));
+                leaves.insert(k, key(step));
+            }
+            let sorted: Vec<(Hash, Hash)> = leaves.iter().map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(tree.root(), reference_root(&sorted, 0), "step {step}");
+            assert_eq!(tree.get(&k), leaves.get(&k));
+        }
+        // The same leaves give the same tree, whatever the history
+        let mut fresh = SparseMerkleTree::new();
+        for (k, v) in &leaves {
+            fresh.insert(*k, *v);
+        }
+        assert_eq!(fresh, tree);
+    }
+
+    #[test]
+    fn test_membership_and_absence_proofs() {
+        for n in 0..=17 {
+            let tree = tree(n);
+            let root = tree.root();
+            for i in 0..n {
+                let proof = tree.prove(&key(i));
+                assert!(
+                    proof.verify(&root, &key(i), Some(&key(i + 1000))),
+                    "n={n} i={i}"
+                );
+                assert!(!proof.verify(&root, &key(i), Some(&key(i))));
+                assert!(!proof.verify(&root, &key(i), None));
+            }
+            for i in n..n + 5 {
+                let proof = tree.prove(&key(i));
+                assert!(proof.verify(&root, &key(i), None), "n={n} i={i}");
+                assert!(!proof.verify(&root, &key(i), Some(&key(i + 1000))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_proof_rejects_tampering() {
+        let tree = tree(8);
+        let root = tree.root();
+        let proof = tree.prove(&key(3));
+        // A proof for one key says nothing about another
+        assert!(!proof.verify(&root, &key(4), None));
+
+        let mut truncated = proof.clone();
+        truncated.siblings.pop();
+        assert!(!truncated.verify(&root, &key(3), Some(&key(1003))));
+
+        // Claiming absence by pointing at a leaf off the key's path
+        let mut absent = tree.prove(&key(100));
+        absent.leaf = Some((key(3), key(1003)));
+        assert!(!absent.verify(&root, &key(100), None));
+    }
+}
@@ -0,0 +1,661 @@
+// state.rs
+// Account state. Transactions execute against a StateOverlay that reads
+// through to the StateStore and buffers every write in a ChangeSet; the store
+// only changes when a ChangeSet is committed, so a block that is rejected or
+// never persisted leaves state untouched.
+//
+// Account state is committed to by a sparse Merkle tree (smt.rs) whose root
+// goes into each block header as state_root. An account's key is the SHA-256
+// of its address and its value the SHA-256 of its canonical encoding; an
+// account with zero balance and nonce is left out of the tree. The store
+// keeps its tree in step with every write, so a commit or undo rehashes only
+// the paths of the accounts it touched.
+
+use crate::encoding::{Encoder, TAG_ACCOUNT, sha256, to_hex};
+use crate::genesis::MAX_SUPPLY;
+use crate::merkle::Hash;
+use crate::receipt::{Event, Receipt, TxStatus};
+use crate::smt::{SparseMerkleProof, SparseMerkleTree};
+use crate::{Address, Block, BlockError, Transaction, TxError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub fn account_key(address: &Address) -> Hash {
+    sha256(&address.0)
+}
+
+// None for an account that is not in the tree
+pub fn account_hash(balance: u64, nonce: u64) -> Option<Hash> {
+    (balance != 0 || nonce != 0)
+        .then(|| sha256(&Encoder::new(TAG_ACCOUNT).u64(balance).u64(nonce).finish()))
+}
+
+// Puts an account's new balance and nonce into the tree
+fn update_account(tree: &mut SparseMerkleTree, address: &Address, balance: u64, nonce: u64) {
+    let key = account_key(address);
+    match account_hash(balance, nonce) {
+        Some(value) => tree.insert(key, value),
+        None => tree.remove(&key),
+    }
+}
+
+// An account's balance and nonce with a proof against a state root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountProof {
+    pub address: Address,
+    pub balance: u64,
+    pub nonce: u64,
+    pub proof: SparseMerkleProof,
+}
+
+impl AccountProof {
+    pub fn verify(&self, state_root: &str) -> bool {
+        let Some(root) = hex::decode(state_root)
+            .ok()
+            .and_then(|bytes| <Hash>::try_from(bytes).ok())
+        else {
+            return false;
+        };
+        self.proof.verify(
+            &root,
+            &account_key(&self.address),
+            account_hash(self.balance, self.nonce).as_ref(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateStore {
+    pub chain_id: String,
+    // Written only through allocate, commit and undo, which keep `tree` in
+    // step
+    balances: HashMap<Address, u64>,
+    nonces: HashMap<Address, u64>,
+    // Fees destroyed so far; balances plus burned is the allocated supply
+    pub burned: u64,
+    tree: SparseMerkleTree,
+}
+
+// Where the native XZN supply sits; total is circulating plus burned and
+// never exceeds max
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Supply {
+    pub max: u64,
+    pub total: u64,
+    pub burned: u64,
+    pub circulating: u64,
+}
+
+// New values of everything a set of transactions touched
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    pub balances: HashMap<Address, u64>,
+    pub nonces: HashMap<Address, u64>,
+    pub burned: Option<u64>,
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.balances.is_empty() && self.nonces.is_empty() && self.burned.is_none()
+    }
+}
+
+// Values a ChangeSet overwrote, kept per block so the block can be undone.
+// None means the account had no entry before.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UndoLog {
+    pub balances: HashMap<Address, Option<u64>>,
+    pub nonces: HashMap<Address, Option<u64>>,
+    pub burned: Option<u64>,
+}
+
+impl StateStore {
+    pub fn new(chain_id: &str) -> Self {
+        StateStore {
+            chain_id: chain_id.to_string(),
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+            burned: 0,
+            tree: SparseMerkleTree::new(),
+        }
+    }
+
+    pub fn balance(&self, account: &Address) -> u64 {
+        self.balances.get(account).copied().unwrap_or(0)
+    }
+
+    pub fn nonce(&self, account: &Address) -> u64 {
+        self.nonces.get(account).copied().unwrap_or(0)
+    }
+
+    pub fn balances(&self) -> &HashMap<Address, u64> {
+        &self.balances
+    }
+
+    pub fn nonces(&self) -> &HashMap<Address, u64> {
+        &self.nonces
+    }
+
+    fn update_tree(&mut self, address: &Address) {
+        let (balance, nonce) = (self.balance(address), self.nonce(address));
+        update_account(&mut self.tree, address, balance, nonce);
+    }
+
+    // Genesis allocation, refused if it would take the supply past MAX_SUPPLY
+    pub fn allocate(&mut self, account: Address, amount: u64) -> Result<(), TxError> {
+        let supply = self.total_supply().ok_or(TxError::Overflow)?;
+        if supply.checked_add(amount).is_none_or(|s| s > MAX_SUPPLY) {
+            return Err(TxError::Overflow);
+        }
+        let balance = self.balances.entry(account).or_insert(0);
+        *balance = balance.checked_add(amount).ok_or(TxError::Overflow)?;
+        self.update_tree(&account);
+        Ok(())
+    }
+
+    // Balances plus burned fees; constant once genesis is allocated
+    pub fn total_supply(&self) -> Option<u64> {
+        self.circulating_supply()?.checked_add(self.burned)
+    }
+
+    // XZN held by accounts, i.e. not burned
+    pub fn circulating_supply(&self) -> Option<u64> {
+        self.balances
+            .values()
+            .try_fold(0u64, |total, balance| total.checked_add(*balance))
+    }
+
+    pub fn supply(&self) -> Option<Supply> {
+        let circulating = self.circulating_supply()?;
+        Some(Supply {
+            max: MAX_SUPPLY,
+            total: circulating.checked_add(self.burned)?,
+            burned: self.burned,
+            circulating,
+        })
+    }
+
+    pub fn state_root(&self) -> String {
+        to_hex(&self.tree.root())
+    }
+
+    pub fn prove(&self, address: &Address) -> AccountProof {
+        AccountProof {
+            address: *address,
+            balance: self.balance(address),
+            nonce: self.nonce(address),
+            proof: self.tree.prove(&account_key(address)),
+        }
+    }
+
+    pub fn overlay(&self) -> StateOverlay<'_> {
+        StateOverlay {
+            base: self,
+            changes: ChangeSet::default(),
+        }
+    }
+
+    // Applies `changes` and returns what they overwrote
+    pub fn commit(&mut self, changes: ChangeSet) -> UndoLog {
+        let undo = UndoLog {
+            balances: changes
+                .balances
+                .keys()
+                .map(|address| (*address, self.balances.get(address).copied()))
+                .collect(),
+            nonces: changes
+                .nonces
+                .keys()
+                .map(|address| (*address, self.nonces.get(address).copied()))
+                .collect(),
+            burned: changes.burned.map(|_| self.burned),
+        };
+        self.balances.extend(&changes.balances);
+        self.nonces.extend(&changes.nonces);
+        for address in changes.balances.keys().chain(changes.nonces.keys()) {
+            self.update_tree(address);
+        }
+        if let Some(burned) = changes.burned {
+            self.burned = burned;
+        }
+        undo
+    }
+
+    // Puts back the values recorded by `commit` and returns the changes that
+    // were undone, which commit can redo. Logs must be undone newest first.
+    pub fn undo(&mut self, undo: &UndoLog) -> ChangeSet {
+        let redo = ChangeSet {
+            balances: undo
+                .balances
+                .keys()
+                .map(|address| (*address, self.balances.get(address).copied().unwrap_or(0)))
+                .collect(),
+            nonces: undo
+                .nonces
+                .keys()
+                .map(|address| (*address, self.nonces.get(address).copied().unwrap_or(0)))
+                .collect(),
+            burned: undo.burned.map(|_| self.burned),
+        };
+        for (address, balance) in &undo.balances {
+            match balance {
+                Some(balance) => self.balances.insert(*address, *balance),
+                None => self.balances.remove(address),
+            };
+        }
+        for (address, nonce) in &undo.nonces {
+            match nonce {
+                Some(nonce) => self.nonces.insert(*address, *nonce),
+                None => self.nonces.remove(address),
+            };
+        }
+        for address in undo.balances.keys().chain(undo.nonces.keys()) {
+            self.update_tree(address);
+        }
+        if let Some(burned) = undo.burned {
+            self.burned = burned;
+        }
+        redo
+    }
+
+    // Executes a single transaction and commits it straight away
+    pub fn apply_tx(&mut self, tx: &Transaction) -> Result<Receipt, TxError> {
+        let mut overlay = self.overlay();
+        let receipt = overlay.apply_tx(tx)?;
+        let changes = overlay.into_changes();
+        self.commit(changes);
+        Ok(receipt)
+    }
+
+    // Re-executes a stored or received block. It must fit in
+    // `max_block_bytes`, every transaction must apply and the receipts and
+    // state root must match the header, otherwise nothing is committed.
+    pub fn apply_block(
+        &mut self,
+        block: &Block,
+        max_block_bytes: u64,
+    ) -> Result<Vec<Receipt>, BlockError> {
+        let (receipts, changes) = self.execute_block(block, max_block_bytes)?;
+        self.commit(changes);
+        Ok(receipts)
+    }
+
+    // The checks of apply_block, returning the changes instead of committing
+    // them
+    pub fn execute_block(
+        &self,
+        block: &Block,
+        max_block_bytes: u64,
+    ) -> Result<(Vec<Receipt>, ChangeSet), BlockError> {
+        block.check_size(max_block_bytes)?;
+        let mut overlay = self.overlay();
+        let receipts = block
+            .transactions
+            .iter()
+            .enumerate()
+            .map(|(position, tx)| {
+                overlay
+                    .apply_tx(tx)
+                    .map_err(|error| BlockError::InvalidTransaction { position, error })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if Receipt::root(&receipts) != block.header.receipts_root {
+            return Err(BlockError::ReceiptsRootMismatch);
+        }
+        if overlay.state_root() != block.header.state_root {
+            return Err(BlockError::StateRootMismatch);
+        }
+        Ok((receipts, overlay.into_changes()))
+    }
+}
+
+pub struct StateOverlay<'a> {
+    base: &'a StateStore,
+    changes: ChangeSet,
+}
+
+impl StateOverlay<'_> {
+    pub fn balance(&self, account: &Address) -> u64 {
+        self.changes
+            .balances
+            .get(account)
+            .or_else(|| self.base.balances.get(account))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    pub fn nonce(&self, account: &Address) -> u64 {
+        self.changes
+            .nonces
+            .get(account)
+            .or_else(|| self.base.nonces.get(account))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    pub fn burned(&self) -> u64 {
+        self.changes.burned.unwrap_or(self.base.burned)
+    }
+
+    // Root the state would have if these changes were committed. The copy
+    // of the store's tree shares its nodes, so only the changed accounts'
+    // paths are rehashed.
+    pub fn state_root(&self) -> String {
+        let mut tree = self.base.tree.clone();
+        for address in self
+            .changes
+            .balances
+            .keys()
+            .chain(self.changes.nonces.keys())
+        {
+            update_account(
+                &mut tree,
+                address,
+                self.balance(address),
+                self.nonce(address),
+            );
+        }
+        to_hex(&tree.root())
+    }
+
+    pub fn into_changes(self) -> ChangeSet {
+        self.changes
+    }
+
+    // Executes `tx` and returns its receipt, or the reason the transaction
+    // cannot be included at all. Once the sender can cover the fee the
+    // transaction is included: the fee is burned and the nonce consumed even
+    // when the transfer itself fails.
+    pub fn apply_tx(&mut self, tx: &Transaction) -> Result<Receipt, TxError> {
+        tx.check(&self.base.chain_id)?;
+        // Nonce check
+        let nonce = self.nonce(&tx.from);
+        if tx.nonce < nonce {
+            return Err(TxError::NonceTooLow {
+                expected: nonce,
+                got: tx.nonce,
+            });
+        }
+        if tx.nonce > nonce {
+            return Err(TxError::NonceTooHigh {
+                expected: nonce,
+                got: tx.nonce,
+            });
+        }
+        // Fee check. Everything is computed before anything is written, so a
+        // rejected transaction leaves no trace.
+        let bal = self.balance(&tx.from);
+        let after_fee = bal
+            .checked_sub(tx.fee)
+            .ok_or(TxError::InsufficientBalance {
+                needed: tx.fee,
+                available: bal,
+            })?;
+        let next_nonce = tx.nonce.checked_add(1).ok_or(TxError::Overflow)?;
+        let burned = self.burned().checked_add(tx.fee).ok_or(TxError::Overflow)?;
+        // Balance check: Some((sender, recipient)) balances if the transfer
+        // goes through
+        let transfer = match after_fee.checked_sub(tx.amount) {
+            None => None,
+            Some(_) if tx.to == tx.from => Some((after_fee, after_fee)),
+            Some(remaining) => {
+                let credited = self
+                    .balance(&tx.to)
+                    .checked_add(tx.amount)
+                    .ok_or(TxError::Overflow)?;
+                Some((remaining, credited))
+            }
+        };
+        // Charge the fee
+        self.changes.balances.insert(tx.from, after_fee);
+        self.changes.nonces.insert(tx.from, next_nonce);
+        self.changes.burned = Some(burned);
+        let mut receipt = Receipt {
+            tx_hash: tx.hash(),
+            status: TxStatus::Success,
+            failure_reason: None,
+            fee_paid: tx.fee,
+            events: Vec::new(),
+        };
+        if tx.fee > 0 {
+            receipt.events.push(Event::FeeBurned {
+                from: tx.from,
+                amount: tx.fee,
+            });
+        }
+        let Some((from_bal, to_bal)) = transfer else {
+            let error = TxError::InsufficientBalance {
+                needed: tx.amount,
+                available: after_fee,
+            };
+            receipt.status = TxStatus::Failed;
+            receipt.failure_reason = Some(error.reason().into());
+            return Ok(receipt);
+        };
+        // Apply
+        self.changes.balances.insert(tx.from, from_bal);
+        self.changes.balances.insert(tx.to, to_bal);
+        receipt.events.push(Event::Transfer {
+            from: tx.from,
+            to: tx.to,
+            amount: tx.amount,
+        });
+        Ok(receipt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Wallet;
+    use crate::genesis::DEVNET_CHAIN_ID;
+
+    fn transfer(wallet: &Wallet, to: Address, amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction {
+            chain_id: DEVNET_CHAIN_ID.into(),
+            from: wallet.address,
+            to,
+            amount,
+            fee: 1,
+            nonce,
+            signature: None,
+        };
+        wallet.sign_transaction(&mut tx);
+        tx
+    }
+
+    #[test]
+    fn test_overlay_leaves_store_untouched_until_commit() {
+        let alice = Wallet::from_secret_key(&[1; 32]);
+        let bob = Address([0xbb; 32]);
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.allocate(alice.address, 100).unwrap();
+
+        let mut overlay = state.overlay();
+        overlay.apply_tx(&transfer(&alice, bob, 10, 0)).unwrap();
+        // Later transactions see earlier ones in the same overlay
+        overlay.apply_tx(&transfer(&alice, bob, 20, 1)).unwrap();
+        assert_eq!(overlay.balance(&alice.address), 68);
+        assert_eq!(overlay.balance(&bob), 30);
+        assert_eq!(overlay.nonce(&alice.address), 2);
+        let changes = overlay.into_changes();
+
+        assert_eq!(state.balances[&alice.address], 100);
+        assert!(!state.balances.contains_key(&bob));
+        assert!(state.nonces.is_empty());
+        assert_eq!(state.burned, 0);
+
+        state.commit(changes);
+        assert_eq!(state.balances[&alice.address], 68);
+        assert_eq!(state.balances[&bob], 30);
+        assert_eq!(state.nonces[&alice.address], 2);
+        assert_eq!(state.burned, 2);
+    }
+
+    #[test]
+    fn test_discarded_overlay_changes_nothing() {
+        let alice = Wallet::from_secret_key(&[1; 32]);
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.allocate(alice.address, 100).unwrap();
+
+        let mut overlay = state.overlay();
+        overlay
+            .apply_tx(&transfer(&alice, Address([0xbb; 32]), 10, 0))
+            .unwrap();
+        assert!(
+            overlay
+                .apply_tx(&transfer(&alice, alice.address, 1, 0))
+                .is_err()
+        );
+        drop(overlay);
+
+        assert!(state.overlay().into_changes().is_empty());
+        assert_eq!(state.balances[&alice.address], 100);
+        assert_eq!(state.total_supply(), Some(100));
+        // The same transaction is still valid against the untouched store
+        assert!(
+            state
+                .apply_tx(&transfer(&alice, Address([0xbb; 32]), 10, 0))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_undo_restores_previous_state() {
+        let alice = Wallet::from_secret_key(&[1; 32]);
+        let bob = Address([0xbb; 32]);
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.allocate(alice.address, 100).unwrap();
+        let genesis = state.clone();
+
+        let mut logs = Vec::new();
+        for nonce in 0..3 {
+            let mut overlay = state.overlay();
+            overlay.apply_tx(&transfer(&alice, bob, 10, nonce)).unwrap();
+            let changes = overlay.into_changes();
+            logs.push((state.clone(), state.commit(changes)));
+        }
+        // The first block created bob and alice's nonce, so undoing it
+        // removes them rather than zeroing them
+        assert_eq!(logs[0].1.balances[&bob], None);
+        assert_eq!(logs[0].1.nonces[&alice.address], None);
+        assert_eq!(logs[0].1.burned, Some(0));
+        assert_eq!(logs[2].1.balances[&alice.address], Some(78));
+
+        let after = state.clone();
+        let mut redo = Vec::new();
+        for (before, undo) in logs.iter().rev() {
+            redo.push(state.undo(undo));
+            assert_eq!(&state, before);
+        }
+        assert_eq!(state, genesis);
+        assert_eq!(state.state_root(), genesis.state_root());
+
+        // What undo returns puts the blocks back
+        for changes in redo.into_iter().rev() {
+            state.commit(changes);
+        }
+        assert_eq!(state, after);
+    }
+
+    #[test]
+    fn test_state_root_vector() {
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        assert_eq!(state.state_root(), crate::ZERO_HASH);
+        state.commit(ChangeSet {
+            balances: HashMap::from([(Address([0xaa; 32]), 100), (Address([0xbb; 32]), 42)]),
+            nonces: HashMap::from([(Address([0xaa; 32]), 3)]),
+            burned: None,
+        });
+        assert_eq!(
+            state.state_root(),
+            "28185b62b4c0390ee528444bd892b3c0a935c43f09a294b230c8c51dfde4a75e"
+        );
+        // Empty accounts are not part of the tree
+        let root = state.state_root();
+        state.commit(ChangeSet {
+            balances: HashMap::from([(Address([0xcc; 32]), 0)]),
+            ..ChangeSet::default()
+        });
+        assert_eq!(state.state_root(), root);
+    }
+
+    #[test]
+    fn test_account_proofs() {
+        let alice = Wallet::from_secret_key(&[1; 32]);
+        let bob = Address([0xbb; 32]);
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.allocate(alice.address, 100).unwrap();
+        state.apply_tx(&transfer(&alice, bob, 10, 0)).unwrap();
+        let root = state.state_root();
+
+        let proof = state.prove(&alice.address);
+        assert_eq!((proof.balance, proof.nonce), (89, 1));
+        assert!(proof.verify(&root));
+        assert!(state.prove(&bob).verify(&root));
+        // Unknown accounts are proven to be empty
+        let nobody = state.prove(&Address([0xcc; 32]));
+        assert_eq!((nobody.balance, nobody.nonce), (0, 0));
+        assert!(nobody.verify(&root));
+
+        let mut inflated = proof.clone();
+        inflated.balance = 1_000;
+        assert!(!inflated.verify(&root));
+        let mut replayed = proof.clone();
+        replayed.nonce = 0;
+        assert!(!replayed.verify(&root));
+        assert!(!proof.verify(crate::ZERO_HASH));
+        assert!(!proof.verify("not hex"));
+    }
+
+    #[test]
+    fn test_overlay_state_root_matches_commit() {
+        let alice = Wallet::from_secret_key(&[1; 32]);
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.allocate(alice.address, 100).unwrap();
+        let before = state.state_root();
+        let mut overlay = state.overlay();
+        overlay
+            .apply_tx(&transfer(&alice, Address([0xbb; 32]), 10, 0))
+            .unwrap();
+        let predicted = overlay.state_root();
+        let changes = overlay.into_changes();
+        assert_ne!(predicted, before);
+        state.commit(changes);
+        assert_eq!(state.state_root(), predicted);
+    }
+
+    #[test]
+    fn test_apply_block_checks_state_root() {
+        let alice = Wallet::from_secret_key(&[1; 32]);
+        let mut state = StateStore::new(DEVNET_CHAIN_ID);
+        state.allocate(alice.address, 100).unwrap();
+        let tx = transfer(&alice, Address([0xbb; 32]), 10, 0);
+        let mut overlay = state.overlay();
+        let receipts = vec![overlay.apply_tx(&tx).unwrap()];
+        let state_root = overlay.state_root();
+        drop(overlay);
+        let block = |state_root: String| {
+            Block::new(
+                1,
+                1,
+                vec![tx.clone()],
+                &receipts,
+                "0".into(),
+                state_root,
+                "p".into(),
+            )
+        };
+
+        assert_eq!(
+            state.apply_block(&block(crate::ZERO_HASH.into()), u64::MAX),
+            Err(BlockError::StateRootMismatch)
+        );
+        assert_eq!(state.balances[&alice.address], 100);
+        assert_eq!(
+            state.apply_block(&block(state_root.clone()), u64::MAX),
+            Ok(receipts.clone())
+        );
+        assert_eq!(state.state_root(), state_root);
+    }
+}
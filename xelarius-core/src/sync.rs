@@ -0,0 +1,732 @@
+// sync.rs
+// Catching up with peers. Gossip only carries blocks as they are made, so a
+// node that starts late or was offline asks peers for what it missed: their
+// status first, then batches of blocks by height. Batches are requested from
+// several peers at once but handed to the chain strictly in height order, so
+// every block is checked against its parent as it is imported. When a batch
+// does not attach, our chain has forked from the peer's; its headers, walking
+// back from there, show the last block we share, and blocks are fetched again
+// from that point.
+//
+// SyncManager only keeps the books. The node sends the requests it hands out
+// and feeds back what peers answer, so the logic is tested without a network.
+
+use crate::error::SyncError;
+use crate::{Block, BlockHeader, Blockchain};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const MAX_BLOCKS_PER_REQUEST: u32 = 64;
+// Answers stop before their blocks' bincode size passes this. The transport
+// takes up to 10 MiB, and its CBOR may spend two bytes per hash byte.
+pub const MAX_RESPONSE_BYTES: u64 = 4 << 20;
+// How many batches may be downloaded beyond the next one to import
+pub const MAX_BATCHES_AHEAD: u64 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlockId {
+    Height(u64),
+    Hash(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncRequest {
+    Status,
+    // Up to `count` canonical blocks, starting with `from`
+    Blocks { from: BlockId, count: u32 },
+    // The same range, headers only
+    Headers { from: BlockId, count: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncResponse {
+    Status(SyncStatus),
+    Blocks(Vec<Block>),
+    Headers(Vec<BlockHeader>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncStatus {
+    // Peers with another genesis are on another network and ignored
+    pub genesis: String,
+    pub height: u64,
+    pub tip: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SyncProgress {
+    // Our canonical tip
+    pub height: u64,
+    // The highest tip a peer reported, or ours if higher
+    pub target: u64,
+    // Peers on our network
+    pub peers: usize,
+    // Requests waiting for an answer
+    pub requests: usize,
+}
+
+impl SyncProgress {
+    pub fn is_syncing(&self) -> bool {
+        self.height < self.target
+    }
+}
+
+// Answers a peer from the canonical chain. Ranges are cut short at the tip,
+// at MAX_BLOCKS_PER_REQUEST and at MAX_RESPONSE_BYTES; a hash that is unknown
+// or on a side branch gets an empty answer.
+pub fn respond(chain: &Blockchain, request: &SyncRequest) -> SyncResponse {
+    match request {
+        SyncRequest::Status => SyncResponse::Status(SyncStatus {
+            genesis: chain.chain[0].hash.clone(),
+            height: chain.tip().header.index,
+            tip: chain.latest_hash(),
+        }),
+        SyncRequest::Blocks { from, count } => {
+            let mut size = 0u64;
+            let blocks = canonical_range(chain, from, *count)
+                .iter()
+                .take_while(|block| {
+                    size = size.saturating_add(bincode::serialized_size(block).unwrap_or(u64::MAX));
+                    size <= MAX_RESPONSE_BYTES
+                })
+                .cloned()
+                .collect();
+            SyncResponse::Blocks(blocks)
+        }
+        SyncRequest::Headers { from, count } => SyncResponse::Headers(
+            canonical_range(chain, from, *count)
+                .iter()
+                .map(|block| block.header.clone())
+                .collect(),
+        ),
+    }
+}
+
+fn canonical_range<'a>(chain: &'a Blockchain, from: &BlockId, count: u32) -> &'a [Block] {
+    let start = match from {
+        BlockId::Height(height) => *height,
+        BlockId::Hash(hash) => match chain.tree.get(hash) {
+            Some(block) => block.header.index,
+            None => return &[],
+        },
+    };
+    let Some(first) = usize::try_from(start)
+        .ok()
+        .filter(|&first| first < chain.chain.len())
+    else {
+        return &[];
+    };
+    if let BlockId::Hash(hash) = from
+        && chain.chain[first].hash != *hash
+    {
+        return &[];
+    }
+    let count = count.min(MAX_BLOCKS_PER_REQUEST) as usize;
+    &chain.chain[first..(first + count).min(chain.chain.len())]
+}
+
+// What a peer is answering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Asked {
+    Blocks { from: u64, count: u32 },
+    Headers { from: u64, count: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncManager<P> {
+    genesis: String,
+    // Tip heights of the peers on our network
+    peers: BTreeMap<P, u64>,
+    // At most one request per peer
+    asked: BTreeMap<P, Asked>,
+    // Ranges not yet requested, by first height
+    queue: BTreeMap<u64, u32>,
+    // Ranges received but not yet imported, by first height
+    downloaded: BTreeMap<u64, (P, Vec<Block>)>,
+    // Every height up to here has been queued
+    planned: u64,
+    // The next height to hand to the chain
+    next: u64,
+    // Set while looking for the last block shared with a peer whose block
+    // at this height did not attach to our chain
+    search: Option<(P, u64)>,
+}
+
+impl<P: Ord + Clone> SyncManager<P> {
+    // `height` is our chain's tip
+    pub fn new(genesis: &str, height: u64) -> Self {
+        SyncManager {
+            genesis: genesis.to_string(),
+            peers: BTreeMap::new(),
+            asked: BTreeMap::new(),
+            queue: BTreeMap::new(),
+            downloaded: BTreeMap::new(),
+            planned: height,
+            next: height + 1,
+            search: None,
+        }
+    }
+
+    pub fn progress(&self, height: u64) -> SyncProgress {
+        SyncProgress {
+            height,
+            target: self.target().max(height),
+            peers: self.peers.len(),
+            requests: self.asked.len(),
+        }
+    }
+
+    fn target(&self) -> u64 {
+        self.peers.values().copied().max().unwrap_or(0)
+    }
+
+    // Nothing queued, downloading or waiting to be imported
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty()
+            && self.downloaded.is_empty()
+            && self.search.is_none()
+            && !self
+                .asked
+                .values()
+                .any(|asked| matches!(asked, Asked::Blocks { .. }))
+    }
+
+    // The peer disconnected or failed to answer. Whatever it was asked for
+    // goes back in the queue.
+    pub fn peer_gone(&mut self, peer: &P) {
+        self.peers.remove(peer);
+        if let Some(Asked::Blocks { from, count }) = self.asked.remove(peer) {
+            self.requeue(from, count);
+        }
+        if matches!(&self.search, Some((searching, _)) if searching == peer) {
+            self.search = None;
+        }
+    }
+
+    fn requeue(&mut self, from: u64, count: u32) {
+        if count > 0 && from <= self.planned && from + count as u64 > self.next {
+            self.queue.insert(from, count);
+        }
+    }
+
+    // Heights below this may be requested; the rest wait until imports
+    // move `next` on
+    fn window(&self) -> u64 {
+        self.next + MAX_BATCHES_AHEAD * MAX_BLOCKS_PER_REQUEST as u64
+    }
+
+    // Queues every height up to the highest peer's tip, but not past the
+    // download window, so a peer claiming an absurd height cannot make the
+    // queue grow without bound. Its claim is corrected by its first short
+    // answer.
+    fn plan(&mut self) {
+        let target = self.target().min(self.window() - 1);
+        while self.planned < target {
+            let count = (target - self.planned).min(MAX_BLOCKS_PER_REQUEST as u64);
+            self.queue.insert(self.planned + 1, count as u32);
+            self.planned += count;
+        }
+    }
+
+    // Hands out requests to peers not already answering one: headers while
+    // looking for a fork point, otherwise the lowest ranges each peer has.
+    // `height` is our chain's tip, which gossip may have moved on since.
+    pub fn next_requests(&mut self, height: u64) -> Vec<(P, SyncRequest)> {
+        if self.is_idle() {
+            self.next = self.next.max(height + 1);
+            self.planned = self.planned.max(height);
+        }
+        let mut requests = Vec::new();
+        if let Some((peer, above)) = self.search.clone() {
+            if !self.asked.contains_key(&peer) {
+                let from = above.saturating_sub(MAX_BLOCKS_PER_REQUEST as u64);
+                let count = (above - from) as u32;
+                self.asked
+                    .insert(peer.clone(), Asked::Headers { from, count });
+                requests.push((
+                    peer,
+                    SyncRequest::Headers {
+                        from: BlockId::Height(from),
+                        count,
+                    },
+                ));
+            }
+            return requests;
+        }
+        self.plan();
+        let window = self.window();
+        let idle: Vec<(P, u64)> = self
+            .peers
+            .iter()
+            .filter(|(peer, _)| !self.asked.contains_key(peer))
+            .map(|(peer, height)| (peer.clone(), *height))
+            .collect();
+        for (peer, peer_height) in idle {
+            let Some((&from, &count)) = self
+                .queue
+                .iter()
+                .find(|&(&from, _)| from <= peer_height && from < window)
+            else {
+                continue;
+            };
+            self.queue.remove(&from);
+            let asked = (count as u64).min(peer_height - from + 1) as u32;
+            if asked < count {
+                self.queue.insert(from + asked as u64, count - asked);
+            }
+            self.asked
+                .insert(peer.clone(), Asked::Blocks { from, count: asked });
+            requests.push((
+                peer,
+                SyncRequest::Blocks {
+                    from: BlockId::Height(from),
+                    count: asked,
+                },
+            ));
+        }
+        requests
+    }
+
+    // Takes a peer's answer. `known` tells whether our block tree holds a
+    // hash. On an error the peer is forgotten until it reports its status
+    // again.
+    pub fn response_received(
+        &mut self,
+        peer: P,
+        response: SyncResponse,
+        known: impl Fn(&str) -> bool,
+    ) -> Result<(), SyncError> {
+        let result = match response {
+            SyncResponse::Status(status) => self.status_received(&peer, status),
+            SyncResponse::Blocks(blocks) => self.blocks_received(&peer, blocks),
+            SyncResponse::Headers(headers) => self.headers_received(&peer, headers, known),
+        };
+        if result.is_err() {
+            self.peer_gone(&peer);
+        }
+        result
+    }
+
+    fn status_received(&mut self, peer: &P, status: SyncStatus) -> Result<(), SyncError> {
+        if status.genesis != self.genesis {
+            return Err(SyncError::WrongGenesis {
+                expected: self.genesis.clone(),
+                got: status.genesis,
+            });
+        }
+        self.peers.insert(peer.clone(), status.height);
+        Ok(())
+    }
+
+    fn blocks_received(&mut self, peer: &P, blocks: Vec<Block>) -> Result<(), SyncError> {
+        let Some(Asked::Blocks { from, count }) = self.asked.get(peer).copied() else {
+            return Err(SyncError::Unexpected);
+        };
+        self.asked.remove(peer);
+        let headers: Vec<&BlockHeader> = blocks.iter().map(|block| &block.header).collect();
+        if !is_range(from, count, &headers)
+            || blocks.iter().any(|block| block.hash != block.header.hash())
+        {
+            self.requeue(from, count);
+            return Err(SyncError::Malformed);
+        }
+        // A short answer means the peer's chain does not reach as far as it
+        // said, so its claimed height is lowered to what it served
+        let received = blocks.len() as u32;
+        if received < count {
+            self.peers.insert(peer.clone(), from + received as u64 - 1);
+            self.requeue(from + received as u64, count - received);
+        }
+        if received > 0 && from <= self.planned && from + received as u64 > self.next {
+            self.downloaded.insert(from, (peer.clone(), blocks));
+        }
+        Ok(())
+    }
+
+    fn headers_received(
+        &mut self,
+        peer: &P,
+        headers: Vec<BlockHeader>,
+        known: impl Fn(&str) -> bool,
+    ) -> Result<(), SyncError> {
+        let Some(Asked::Headers { from, count }) = self.asked.get(peer).copied() else {
+            return Err(SyncError::Unexpected);
+        };
+        self.asked.remove(peer);
+        if !matches!(&self.search, Some((searching, _)) if searching == peer) {
+            return Ok(());
+        }
+        let refs: Vec<&BlockHeader> = headers.iter().collect();
+        if headers.is_empty() || !is_range(from, count, &refs) {
+            return Err(SyncError::Malformed);
+        }
+        match headers.iter().rev().find(|header| known(&header.hash())) {
+            Some(shared) => {
+                self.search = None;
+                self.restart_at(shared.index + 1);
+                self.plan();
+            }
+            // Genesis matched at status, so a chain that shares nothing
+            // down to it cannot be the one the peer reported
+            None if from == 0 => return Err(SyncError::NoCommonAncestor),
+            None => self.search = Some((peer.clone(), from)),
+        }
+        Ok(())
+    }
+
+    // The next downloaded blocks in height order, for the caller to import
+    // one at a time; None until the lowest missing range arrives
+    pub fn next_batch(&mut self) -> Option<(P, Vec<Block>)> {
+        loop {
+            let entry = self.downloaded.first_entry()?;
+            if *entry.key() > self.next {
+                return None;
+            }
+            let (peer, mut blocks) = entry.remove();
+            blocks.retain(|block| block.header.index >= self.next);
+            if let Some(last) = blocks.last() {
+                self.next = last.header.index + 1;
+                return Some((peer, blocks));
+            }
+        }
+    }
+
+    // The block from `peer` at `height` does not attach to our chain: look
+    // for where the two forked before fetching anything else
+    pub fn forked(&mut self, peer: P, height: u64) {
+        self.restart_at(height);
+        self.search = Some((peer, height));
+    }
+
+    // The block from `peer` at `height` failed validation: forget the peer
+    // and fetch from that height again
+    pub fn invalid(&mut self, peer: &P, height: u64) {
+        self.peers.remove(peer);
+        self.restart_at(height);
+    }
+
+    fn restart_at(&mut self, next: u64) {
+        self.next = next;
+        self.planned = next - 1;
+        self.queue.clear();
+        self.downloaded.clear();
+    }
+}
+
+// Consecutive heights starting at `from`, each linked to the one before, and
+// no more than asked for
+fn is_range(from: u64, count: u32, headers: &[&BlockHeader]) -> bool {
+    headers.len() <= count as usize
+        && headers
+            .iter()
+            .enumerate()
+            .all(|(i, header)| header.index == from + i as u64)
+        && headers
+            .windows(2)
+            .all(|pair| pair[1].previous_hash == pair[0].hash())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChangeSet, StateStore, ZERO_HASH};
+
+    // Appends empty blocks up to `height`; `proposer` tells forks apart
+    fn extend(chain: &mut Blockchain, height: u64, proposer: &str) {
+        while chain.tip().header.index < height {
+            let timestamp = chain.tip().header.timestamp + 1;
+            let block = chain.next_block(vec![], &[], timestamp, ZERO_HASH.into(), proposer.into());
+            chain
+                .append(block, &mut StateStore::new("test"), ChangeSet::default())
+                .unwrap();
+        }
+    }
+
+    fn chain_of(height: u64) -> Blockchain {
+        let mut chain = Blockchain::new();
+        extend(&mut chain, height, "a");
+        chain
+    }
+
+    // A chain sharing `shared`'s blocks up to `fork`, then its own up to `height`
+    fn fork_of(shared: &Blockchain, fork: u64, height: u64) -> Blockchain {
+        let mut chain = Blockchain::new();
+        for block in &shared.chain[1..=fork as usize] {
+            chain
+                .append(
+                    block.clone(),
+                    &mut StateStore::new("test"),
+                    ChangeSet::default(),
+                )
+                .unwrap();
+        }
+        extend(&mut chain, height, "b");
+        chain
+    }
+
+    // Plays the node's part: sends every request to the peer's chain, feeds
+    // the answers back and imports what is ready, until nothing is left to
+    // ask. Returns every request made.
+    fn sync(
+        manager: &mut SyncManager<u8>,
+        local: &mut Blockchain,
+        peers: &[&Blockchain],
+    ) -> Vec<(u8, SyncRequest)> {
+        let genesis = StateStore::new("test");
+        let mut state = genesis.clone();
+        let mut sent = Vec::new();
+        for (peer, chain) in peers.iter().enumerate() {
+            let status = respond(chain, &SyncRequest::Status);
+            manager
+                .response_received(peer as u8, status, |_| false)
+                .unwrap();
+        }
+        loop {
+            'batches: while let Some((peer, blocks)) = manager.next_batch() {
+                for block in blocks {
+                    let height = block.header.index;
+                    if !local.tree.contains(&block.header.previous_hash) {
+                        manager.forked(peer, height);
+                        break 'batches;
+                    }
+                    if local.import_block(block, &mut state).is_err() {
+                        manager.invalid(&peer, height);
+                        break 'batches;
+                    }
+                }
+            }
+            let requests = manager.next_requests(local.tip().header.index);
+            if requests.is_empty() {
+                return sent;
+            }
+            for (peer, request) in requests {
+                let response = respond(peers[peer as usize], &request);
+                manager
+                    .response_received(peer, response, |hash| local.tree.contains(hash))
+                    .unwrap();
+                sent.push((peer, request));
+            }
+        }
+    }
+
+    #[test]
+    fn test_respond_serves_canonical_ranges() {
+        let mut chain = chain_of(100);
+        let side = fork_of(&chain, 50, 51);
+        chain
+            .import_block(side.tip().clone(), &mut StateStore::new("test"))
+            .unwrap();
+
+        let SyncResponse::Status(status) = respond(&chain, &SyncRequest::Status) else {
+            panic!("expected a status");
+        };
+        assert_eq!(status.genesis, chain.chain[0].hash);
+        assert_eq!((status.height, status.tip), (100, chain.latest_hash()));
+
+        let blocks = |from, count| match respond(&chain, &SyncRequest::Blocks { from, count }) {
+            SyncResponse::Blocks(blocks) => blocks
+                .iter()
+                .map(|block| block.header.index)
+                .collect::<Vec<_>>(),
+            _ => panic!("expected blocks"),
+        };
+        assert_eq!(blocks(BlockId::Height(3), 4), vec![3, 4, 5, 6]);
+        assert_eq!(blocks(BlockId::Height(98), 10), vec![98, 99, 100]);
+        assert_eq!(blocks(BlockId::Height(101), 10), Vec::<u64>::new());
+        assert_eq!(
+            blocks(BlockId::Height(0), 1_000).len(),
+            MAX_BLOCKS_PER_REQUEST as usize
+        );
+        let hash = chain.chain[40].hash.clone();
+        assert_eq!(blocks(BlockId::Hash(hash), 2), vec![40, 41]);
+        // Side branches and unknown hashes are not served
+        assert!(blocks(BlockId::Hash(side.latest_hash()), 2).is_empty());
+        assert!(blocks(BlockId::Hash(ZERO_HASH.into()), 2).is_empty());
+
+        let SyncResponse::Headers(headers) = respond(
+            &chain,
+            &SyncRequest::Headers {
+                from: BlockId::Height(10),
+                count: 3,
+            },
+        ) else {
+            panic!("expected headers");
+        };
+        assert_eq!(
+            headers,
+            vec![
+                chain.chain[10].header.clone(),
+                chain.chain[11].header.clone(),
+                chain.chain[12].header.clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_syncs_from_several_peers_in_parallel() {
+        let full = chain_of(300);
+        let partial = fork_of(&full, 150, 150);
+        let mut local = fork_of(&full, 10, 10);
+        let mut manager = SyncManager::new(&local.chain[0].hash, 10);
+
+        let sent = sync(&mut manager, &mut local, &[&full, &partial]);
+        assert_eq!(local.latest_hash(), full.latest_hash());
+        // Both peers served batches, and the one that stops at 150 was never
+        // asked for more
+        assert!(sent.iter().any(|(peer, _)| *peer == 0));
+        assert!(sent.iter().any(|(peer, _)| *peer == 1));
+        for (peer, request) in &sent {
+            let SyncRequest::Blocks {
+                from: BlockId::Height(from),
+                count,
+            } = request
+            else {
+                panic!("unexpected {:?}", request);
+            };
+            if *peer == 1 {
+                assert!(from + *count as u64 - 1 <= 150);
+            }
+        }
+        let progress = manager.progress(local.tip().header.index);
+        assert_eq!((progress.height, progress.target), (300, 300));
+        assert!(!progress.is_syncing());
+
+        // Caught up: nothing more to ask until a peer reports a higher tip
+        assert!(manager.next_requests(300).is_empty());
+    }
+
+    #[test]
+    fn test_finds_fork_point_and_reorgs() {
+        let shared = chain_of(200);
+        let peer = fork_of(&shared, 30, 250);
+        let mut local = fork_of(&shared, 30, 30);
+        extend(&mut local, 120, "c");
+        let mut manager = SyncManager::new(&local.chain[0].hash, 120);
+
+        let sent = sync(&mut manager, &mut local, &[&peer]);
+        assert_eq!(local.latest_hash(), peer.latest_hash());
+        // Two rounds of headers walk back from 121 past the fork at 30
+        let searches: Vec<_> = sent
+            .iter()
+            .filter_map(|(_, request)| match request {
+                SyncRequest::Headers {
+                    from: BlockId::Height(from),
+                    ..
+                } => Some(*from),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(searches, vec![57, 0]);
+    }
+
+    #[test]
+    fn test_drops_peers_with_bad_answers() {
+        let chain = chain_of(100);
+        let genesis = chain.chain[0].hash.clone();
+        let mut manager = SyncManager::new(&genesis, 0);
+
+        let other = SyncResponse::Status(SyncStatus {
+            genesis: ZERO_HASH.into(),
+            height: 1_000,
+            tip: ZERO_HASH.into(),
+        });
+        assert!(matches!(
+            manager.response_received(1, other, |_| false),
+            Err(SyncError::WrongGenesis { .. })
+        ));
+        assert_eq!(manager.progress(0).peers, 0);
+        // Answers nobody asked for
+        assert_eq!(
+            manager.response_received(1, SyncResponse::Blocks(vec![]), |_| false),
+            Err(SyncError::Unexpected)
+        );
+
+        let status = respond(&chain, &SyncRequest::Status);
+        for peer in [1, 2] {
+            manager
+                .response_received(peer, status.clone(), |_| false)
+                .unwrap();
+        }
+        let requests = manager.next_requests(0);
+        assert_eq!(requests.len(), 2);
+        let (peer, request) = requests[0].clone();
+        let SyncResponse::Blocks(mut blocks) = respond(&chain, &request) else {
+            panic!("expected blocks");
+        };
+        blocks.swap(3, 4);
+        assert_eq!(
+            manager.response_received(peer, SyncResponse::Blocks(blocks), |_| false),
+            Err(SyncError::Malformed)
+        );
+        assert_eq!(manager.progress(0).peers, 1);
+
+        // The dropped peer's range goes to the other one once it is free,
+        // and the download completes from it alone
+        let (other, request) = requests[1].clone();
+        let response = respond(&chain, &request);
+        manager
+            .response_received(other, response, |_| false)
+            .unwrap();
+        let requests = manager.next_requests(0);
+        assert_eq!(
+            requests,
+            vec![(
+                other,
+                SyncRequest::Blocks {
+                    from: BlockId::Height(1),
+                    count: MAX_BLOCKS_PER_REQUEST
+                }
+            )]
+        );
+        let response = respond(&chain, &requests[0].1);
+        manager
+            .response_received(other, response, |_| false)
+            .unwrap();
+        let heights: Vec<u64> = std::iter::from_fn(|| manager.next_batch())
+            .flat_map(|(_, blocks)| blocks)
+            .map(|block| block.header.index)
+            .collect();
+        assert_eq!(heights, (1..=100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_huge_claimed_height_stays_within_the_window() {
+        let chain = chain_of(100);
+        let mut manager = SyncManager::new(&chain.chain[0].hash, 0);
+        let liar = SyncResponse::Status(SyncStatus {
+            genesis: chain.chain[0].hash.clone(),
+            height: u64::MAX,
+            tip: ZERO_HASH.into(),
+        });
+        manager.response_received(1, liar, |_| false).unwrap();
+
+        let mut requests = manager.next_requests(0);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            manager.queue.len() as u64,
+            MAX_BATCHES_AHEAD - 1,
+            "only the download window is planned"
+        );
+        assert_eq!(
+            manager.planned,
+            MAX_BATCHES_AHEAD * MAX_BLOCKS_PER_REQUEST as u64
+        );
+
+        // Its answers show how far its chain really reaches
+        for _ in 0..2 {
+            let (peer, request) = requests.remove(0);
+            let response = respond(&chain, &request);
+            manager
+                .response_received(peer, response, |_| false)
+                .unwrap();
+            requests = manager.next_requests(0);
+        }
+        assert_eq!(manager.progress(0).target, 100);
+        let heights: Vec<u64> = std::iter::from_fn(|| manager.next_batch())
+            .flat_map(|(_, blocks)| blocks)
+            .map(|block| block.header.index)
+            .collect();
+        assert_eq!(heights, (1..=100).collect::<Vec<_>>());
+        assert!(manager.next_requests(100).is_empty());
+    }
+}
@@ -1,25 +1,349 @@
 // chain.rs
 // Handles blockchain logic and wraps xelarius-core
 
-use xelarius_core::{Blockchain, Mempool, PersistentChain, StateStore};
-use tracing::{info, error};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+use xelarius_core::clock::Clock;
+use xelarius_core::genesis::GenesisConfig;
+use xelarius_core::message::NetworkMessage;
+use xelarius_core::service::{self, Production};
+use xelarius_core::{
+    Block, BlockError, Blockchain, ChainUpdate, ChangeSet, ImportError, LoadError, Mempool,
+    PersistentChain, StateStore, Transaction, TxError, TxHash,
+};
 
-pub fn init_chain() -> std::sync::Arc<std::sync::Mutex<Blockchain>> {
-    info!("Initializing blockchain...");
-    std::sync::Arc::new(std::sync::Mutex::new(Blockchain::new()))
+pub type SharedChain = Arc<Mutex<Blockchain>>;
+pub type SharedState = Arc<Mutex<StateStore>>;
+
+// Everything the node's tasks share: the chain and state in memory, the
+// database behind them and the mempool. Locks are always taken chain first,
+// then state.
+#[derive(Clone)]
+pub struct ChainService {
+    pub chain: SharedChain,
+    pub state: SharedState,
+    pub mempool: Arc<Mempool>,
+    pub db: PersistentChain,
+}
+
+// What became of a message from a peer. Gossipsub forwards only accepted
+// messages and counts rejected ones against the peer that sent them; ignored
+// ones are dropped without blame, e.g. because this node is behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Ignore,
+    Reject,
 }
 
-pub fn init_mempool() -> std::sync::Arc<Mempool> {
+impl ChainService {
+    // Handles a message from a peer: blocks are imported, transactions go
+    // into the mempool
+    pub fn handle_message(&self, message: NetworkMessage) -> Verdict {
+        match message {
+            NetworkMessage::NewBlock(block) => {
+                if self.chain.lock().unwrap().tree.contains(&block.hash) {
+                    return Verdict::Ignore;
+                }
+                let (index, hash) = (block.header.index, block.hash.clone());
+                match self.import_block(block) {
+                    Ok(update) if update.is_reorg() => info!(
+                        "Reorganized to block {} ({}): {} blocks reverted, {} applied",
+                        index,
+                        hash,
+                        update.reverted.len(),
+                        update.applied.len()
+                    ),
+                    // Kept in case its branch wins, but not executed, so it
+                    // is not vouched for to other peers
+                    Ok(update) if update.applied.is_empty() => {
+                        info!("Stored block {} ({}) on a side branch", index, hash);
+                        return Verdict::Ignore;
+                    }
+                    Ok(_) => info!("Imported block {} ({})", index, hash),
+                    Err(e) => {
+                        warn!("Rejected block {} ({}): {}", index, hash, e);
+                        return match e {
+                            // We are behind, or our clock is, and sync catches
+                            // up; or the block is on a fork we no longer follow
+                            ImportError::Invalid(
+                                BlockError::UnknownParent { .. }
+                                | BlockError::TimestampTooFarAhead { .. }
+                                | BlockError::ForkTooDeep { .. },
+                            )
+                            | ImportError::Store(_) => Verdict::Ignore,
+                            ImportError::Invalid(_) => Verdict::Reject,
+                        };
+                    }
+                }
+                Verdict::Accept
+            }
+            NetworkMessage::NewTx(tx) => {
+                let hash = tx.hash();
+                match self.admit_tx(tx) {
+                    Ok(_) => {
+                        info!("Transaction {} received from a peer", hash);
+                        Verdict::Accept
+                    }
+                    // Depends on state that differs between honest peers:
+                    // the sender's nonce and balance, the recipient's
+                    // balance, or what is already waiting. Only what the
+                    // transaction says about itself is the relayer's fault.
+                    Err(
+                        TxError::AlreadyKnown
+                        | TxError::NonceTooLow { .. }
+                        | TxError::NonceTooHigh { .. }
+                        | TxError::InsufficientBalance { .. }
+                        | TxError::Overflow,
+                    ) => Verdict::Ignore,
+                    Err(e) => {
+                        warn!("Rejected transaction {} from a peer: {}", hash, e);
+                        Verdict::Reject
+                    }
+                }
+            }
+        }
+    }
+
+    // Adds a transaction from a peer or an RPC client to the mempool, so
+    // stale or unfundable ones are neither kept nor relayed. After the
+    // mempool's stateless checks, the transaction is applied to the tip's
+    // state on top of the sender's transactions already waiting, so a sender
+    // can queue several. The state lock is held until the transaction is in,
+    // so two admitted at once cannot both take the same nonce.
+    pub fn admit_tx(&self, tx: Transaction) -> Result<TxHash, TxError> {
+        self.mempool.check_tx(&tx)?;
+        let state = self.state.lock().unwrap();
+        let mut overlay = state.overlay();
+        for pending in self.mempool.pending_from(&tx.from) {
+            // Ones the tip has made stale fail here and change nothing
+            let _ = overlay.apply_tx(&pending);
+        }
+        overlay.apply_tx(&tx)?;
+        self.mempool.add_tx(tx)
+    }
+
+    // Imports a block and writes it to the database; see
+    // service::import_block
+    pub fn import_block(&self, block: Block) -> Result<ChainUpdate, ImportError> {
+        let mut chain = self.chain.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        service::import_block(&mut chain, &mut state, &self.db, block)
+    }
+
+    // One turn of the producer on the current tip; see service::produce_once
+    pub fn produce(&self, proposer: &str) -> Production {
+        let mut chain = self.chain.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        service::produce_once(&mut chain, &mut state, &self.mempool, &self.db, proposer)
+    }
+}
+
+pub fn init_mempool(
+    genesis: &GenesisConfig,
+    clock: Arc<dyn Clock>,
+    max_tx_age: Option<u64>,
+) -> Arc<Mempool> {
     info!("Initializing mempool...");
-    std::sync::Arc::new(Mempool::new())
+    let mut mempool = Mempool::new(&genesis.chain_id);
+    mempool.min_fee = genesis.params.min_fee;
+    mempool.max_block_bytes = genesis.params.max_block_bytes;
+    mempool.max_tx_age = max_tx_age;
+    mempool.clock = clock;
+    Arc::new(mempool)
 }
 
-pub fn init_db() -> PersistentChain {
-    info!("Opening persistent chain database...");
-    PersistentChain::open("/tmp/xelarius_chain").expect("sled open")
+pub fn init_db(path: &str) -> PersistentChain {
+    info!("Opening persistent chain database at {}...", path);
+    PersistentChain::open(path).expect("sled open")
 }
 
-pub fn init_state() -> std::sync::Arc<std::sync::Mutex<StateStore>> {
-    info!("Initializing state store...");
-    std::sync::Arc::new(std::sync::Mutex::new(StateStore::new()))
+// Resumes from the stored head, or starts a new chain at genesis if the
+// database is empty. `genesis_block`, `genesis_state` and `max_block_bytes`
+// come from the chain spec.
+pub fn init_chain(
+    db: &PersistentChain,
+    genesis_block: &Block,
+    genesis_state: &StateStore,
+    max_block_bytes: u64,
+    reexecute: bool,
+) -> Result<(SharedChain, SharedState), LoadError> {
+    info!("Loading blockchain...");
+    let chain = db.load_chain(genesis_block, max_block_bytes)?;
+    if db.head()?.is_none() {
+        let allocations = ChangeSet {
+            balances: genesis_state.balances().clone(),
+            ..ChangeSet::default()
+        };
+        db.store_block(genesis_block, &[], &allocations)?;
+    }
+    let state = if reexecute {
+        info!("Re-executing {} blocks from genesis...", chain.chain.len());
+        db.reexecute(&chain, genesis_state.clone())?
+    } else {
+        db.load_state(&genesis_state.chain_id)?
+    };
+    // A cheap check on every start: the state must be the one the head
+    // committed to; --reexecute finds which block went wrong
+    if state.state_root() != chain.tip().header.state_root {
+        return Err(LoadError::StateRootMismatch {
+            index: chain.tip().header.index,
+        });
+    }
+    info!(
+        "Chain head is block {} ({})",
+        chain.tip().header.index,
+        chain.latest_hash()
+    );
+    Ok((Arc::new(Mutex::new(chain)), Arc::new(Mutex::new(state))))
+}
+
+// Maintenance: rewinds the database to block `height` and checks that the
+// state left behind is the one that block committed to. Returns the new head.
+pub fn revert_to(
+    db: &PersistentChain,
+    genesis_block: &Block,
+    chain_id: &str,
+    max_block_bytes: u64,
+    height: u64,
+) -> Result<Block, LoadError> {
+    info!("Reverting chain database to block {}...", height);
+    db.revert_to(height)?;
+    let chain = db.load_chain(genesis_block, max_block_bytes)?;
+    let state = db.load_state(chain_id)?;
+    if state.state_root() != chain.tip().header.state_root {
+        return Err(LoadError::StateRootMismatch {
+            index: chain.tip().header.index,
+        });
+    }
+    Ok(chain.tip().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xelarius_core::clock::SystemClock;
+    use xelarius_core::genesis::Allocation;
+    use xelarius_core::{Address, Wallet};
+
+    // A node at the genesis of a devnet whose only allocation is `funds` to
+    // `wallet`, with a minimum fee of 1
+    fn funded_service(wallet: &Wallet, funds: u64) -> (ChainService, tempfile::TempDir) {
+        let mut genesis = GenesisConfig::devnet();
+        genesis.allocations = vec![Allocation {
+            address: wallet.address,
+            amount: funds,
+        }];
+        genesis.params.min_fee = 1;
+        let (genesis_block, genesis_state) = genesis.build().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let db = init_db(dir.path().to_str().unwrap());
+        let (chain, state) =
+            init_chain(&db, &genesis_block, &genesis_state, u64::MAX, false).unwrap();
+        let mempool = init_mempool(&genesis, Arc::new(SystemClock), None);
+        let service = ChainService {
+            chain,
+            state,
+            mempool,
+            db,
+        };
+        (service, dir)
+    }
+
+    fn transfer(wallet: &Wallet, amount: u64, fee: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction {
+            chain_id: xelarius_core::genesis::DEVNET_CHAIN_ID.into(),
+            from: wallet.address,
+            to: Address([0xbb; 32]),
+            amount,
+            fee,
+            nonce,
+            signature: None,
+        };
+        wallet.sign_transaction(&mut tx);
+        tx
+    }
+
+    #[test]
+    fn test_tx_verdicts_blame_peers_only_for_stateless_failures() {
+        let wallet = Wallet::generate();
+        let (service, _dir) = funded_service(&wallet, 100);
+        let verdict = |tx| service.handle_message(NetworkMessage::NewTx(tx));
+
+        assert_eq!(verdict(transfer(&wallet, 10, 1, 0)), Verdict::Accept);
+        assert_eq!(verdict(transfer(&wallet, 10, 1, 0)), Verdict::Ignore);
+        // The peer may not have seen what this node has, or the reverse
+        assert_eq!(verdict(transfer(&wallet, 10, 1, 5)), Verdict::Ignore);
+        assert_eq!(verdict(transfer(&wallet, 0, 1000, 1)), Verdict::Ignore);
+        // Invalid whatever the state, even with a nonce that does not fit
+        assert_eq!(verdict(transfer(&wallet, 10, 0, 5)), Verdict::Reject);
+        let mut forged = transfer(&wallet, 10, 1, 5);
+        forged.amount = 11;
+        assert_eq!(verdict(forged), Verdict::Reject);
+        let mut unsigned = transfer(&wallet, 10, 1, 5);
+        unsigned.signature = None;
+        assert_eq!(verdict(unsigned), Verdict::Reject);
+    }
+
+    #[test]
+    fn test_admit_tx_counts_the_senders_pending_txs() {
+        let wallet = Wallet::generate();
+        let (service, _dir) = funded_service(&wallet, 100);
+
+        // Nonces follow on from the ones waiting, not from the tip
+        for nonce in 0..3 {
+            service.admit_tx(transfer(&wallet, 10, 1, nonce)).unwrap();
+        }
+        assert_eq!(
+            service.admit_tx(transfer(&wallet, 10, 1, 2)),
+            Err(TxError::NonceTooLow {
+                expected: 3,
+                got: 2
+            })
+        );
+        // So does the balance: 33 of 100 is already spoken for
+        assert_eq!(
+            service.admit_tx(transfer(&wallet, 0, 68, 3)),
+            Err(TxError::InsufficientBalance {
+                needed: 68,
+                available: 67
+            })
+        );
+        service.admit_tx(transfer(&wallet, 66, 1, 3)).unwrap();
+
+        // Once they are in a block, the tip's state takes over
+        let production = service.produce("p");
+        assert!(production.block.unwrap().1.is_ok());
+        assert!(service.mempool.pending_from(&wallet.address).is_empty());
+        assert_eq!(
+            service.admit_tx(transfer(&wallet, 0, 1, 3)),
+            Err(TxError::NonceTooLow {
+                expected: 4,
+                got: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_init_chain_refuses_state_not_matching_head() {
+        let (genesis_block, genesis_state) = GenesisConfig::devnet().build().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let db = init_db(dir.path().to_str().unwrap());
+        init_chain(&db, &genesis_block, &genesis_state, u64::MAX, false).unwrap();
+
+        // Stored state that block 0 does not commit to, e.g. after a
+        // damaged write
+        let dir = tempfile::tempdir().unwrap();
+        let db = init_db(dir.path().to_str().unwrap());
+        let mut changes = ChangeSet {
+            balances: genesis_state.balances().clone(),
+            ..ChangeSet::default()
+        };
+        changes.balances.insert(Address([0xcc; 32]), 1);
+        db.store_block(&genesis_block, &[], &changes).unwrap();
+        assert!(matches!(
+            init_chain(&db, &genesis_block, &genesis_state, u64::MAX, false),
+            Err(LoadError::StateRootMismatch { index: 0 })
+        ));
+    }
 }
@@ -0,0 +1,84 @@
+// config.rs
+// Command-line options for the node
+
+use clap::{Parser, Subcommand};
+use libp2p::Multiaddr;
+
+#[derive(Debug, Parser)]
+#[command(name = "xelarius-node", about = "Run a Xelarius node")]
+pub struct Config {
+    #[arg(
+        long,
+        default_value = "/tmp/xelarius_chain",
+        help = "Directory holding the chain database"
+    )]
+    pub data_dir: String,
+
+    #[arg(
+        long,
+        help = "JSON chain spec defining genesis; the built-in devnet spec if omitted"
+    )]
+    pub chain_spec: Option<String>,
+
+    #[arg(
+        long,
+        help = "Re-execute every stored block from genesis and check the stored state before starting"
+    )]
+    pub reexecute: bool,
+
+    #[arg(
+        long,
+        help = "Drop mempool transactions not included within this many seconds; kept until included if omitted"
+    )]
+    pub tx_max_age: Option<u64>,
+
+    #[arg(
+        long,
+        default_value_t = xelarius_core::MAX_FUTURE_DRIFT,
+        help = "Reject received blocks timestamped more than this many seconds ahead of local time"
+    )]
+    pub max_future_drift: u64,
+
+    #[arg(
+        long = "listen",
+        default_value = "/ip4/0.0.0.0/tcp/4000",
+        help = "Multiaddr to accept peer connections on; repeat for several"
+    )]
+    pub listen: Vec<Multiaddr>,
+
+    #[arg(
+        long = "bootstrap",
+        help = "Multiaddr of a peer to join the network through, e.g. /dns4/node1/tcp/4000/p2p/<peer id>; repeat for several"
+    )]
+    pub bootstrap: Vec<Multiaddr>,
+
+    #[arg(
+        long,
+        help = "File holding the node's hex-encoded ed25519 secret key, which fixes its peer ID; <data-dir>/node_key, created on first run, if omitted"
+    )]
+    pub node_key: Option<String>,
+
+    #[arg(long, help = "Do not look for peers on the local network with mDNS")]
+    pub no_mdns: bool,
+
+    #[arg(
+        long,
+        default_value = "127.0.0.1:8545",
+        help = "Address the JSON-RPC server listens on"
+    )]
+    pub rpc_addr: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Maintenance commands run against the database and exit instead of
+// starting the node
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Undo every block above HEIGHT, making it the chain head again")]
+    RevertTo {
+        #[arg(help = "Height of the block to keep as the new head")]
+        height: u64,
+    },
+}
@@ -1,47 +1,157 @@
-use futures::StreamExt;
-use libp2p::{
-    core::upgrade,
-    dns::TokioDnsConfig,
-    gossipsub::{
-        Behaviour as Gossipsub, Config as GossipsubConfig, Event as GossipsubEvent,
-        IdentTopic, MessageAuthenticity,
-    },
-    identity,
-    noise::{Keypair as NoiseKeypair, NoiseConfig, X25519Spec},
-    swarm::SwarmBuilder,
-    tcp::tokio::Transport,
-    yamux::YamuxConfig,
-    PeerId, Transport as Libp2pTransport,
-};
-use serde_json::json;
+use clap::Parser;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
-use xelarius_core::{Blockchain, Mempool, PersistentChain, Transaction, StateStore, Token};
+use xelarius_core::clock::{Clock, SystemClock};
+use xelarius_core::genesis::GenesisConfig;
+use xelarius_core::keystore::Keystore;
 
-mod network;
 mod chain;
+mod config;
+mod network;
+mod node_key;
 mod rpc;
+mod sync;
 mod tasks;
 
 #[tokio::main]
 async fn main() {
-    // Setup core blockchain, mempool, persistent storage, state
-    let chain = chain::init_chain();
-    let mempool = chain::init_mempool();
-    let db = chain::init_db();
-    let state = chain::init_state();
-
-    // Setup networking (libp2p, gossipsub, etc.)
-    let (net_tx, net_rx, local_peer_id) = network::setup_network().await;
+    let config = config::Config::parse();
+
+    // Build genesis from the chain spec
+    let spec = match &config.chain_spec {
+        Some(path) => GenesisConfig::load(path),
+        None => Ok(GenesisConfig::devnet()),
+    };
+    let (genesis, (genesis_block, genesis_state)) =
+        match spec.and_then(|spec| spec.build().map(|built| (spec, built))) {
+            Ok(built) => built,
+            Err(e) => {
+                eprintln!("Cannot build genesis: {}", e);
+                std::process::exit(1);
+            }
+        };
+    println!(
+        "Chain {} genesis hash: {}",
+        genesis.chain_id, genesis_block.hash
+    );
+
+    // Setup persistent storage, then resume the chain and state from it
+    let db = chain::init_db(&config.data_dir);
+    if let Some(config::Command::RevertTo { height }) = config.command {
+        match chain::revert_to(
+            &db,
+            &genesis_block,
+            &genesis.chain_id,
+            genesis.params.max_block_bytes,
+            height,
+        ) {
+            Ok(head) => println!(
+                "Chain head is now block {} ({})",
+                head.header.index, head.hash
+            ),
+            Err(e) => {
+                eprintln!(
+                    "Cannot revert {} to block {}: {}",
+                    config.data_dir, height, e
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let (chain, state) = match chain::init_chain(
+        &db,
+        &genesis_block,
+        &genesis_state,
+        genesis.params.max_block_bytes,
+        config.reexecute,
+    ) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Cannot resume chain from {}: {}", config.data_dir, e);
+            std::process::exit(1);
+        }
+    };
+    // Block timestamps, mempool arrival times and network latency all read
+    // the same clock
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    {
+        let mut chain = chain.lock().unwrap();
+        chain.clock = clock.clone();
+        chain.max_future_drift = config.max_future_drift;
+    }
+    let mempool = chain::init_mempool(&genesis, clock.clone(), config.tx_max_age);
+    let service = chain::ChainService {
+        chain,
+        state,
+        mempool,
+        db,
+    };
+
+    // The peer ID stays the same across restarts
+    let key_path = match &config.node_key {
+        Some(path) => Path::new(path).to_owned(),
+        None => Path::new(&config.data_dir).join(node_key::NODE_KEY_FILE),
+    };
+    let id_keys = match node_key::load_or_create(&key_path, config.node_key.is_none()) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let keystore = match Keystore::open(Path::new(&config.data_dir).join(rpc::KEYSTORE_DIR)) {
+        Ok(keystore) => Arc::new(keystore),
+        Err(e) => {
+            eprintln!("Cannot open keystore: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Setup networking (libp2p, gossipsub, block sync, etc.)
+    let sync_progress = Arc::new(Mutex::new(Default::default()));
+    let network_config = network::NetworkConfig {
+        listen: config.listen,
+        bootstrap: config.bootstrap,
+        mdns: !config.no_mdns,
+    };
+    let (net_tx, local_peer_id) = match network::setup_network(
+        clock.clone(),
+        id_keys,
+        network_config,
+        service.clone(),
+        sync_progress.clone(),
+    )
+    .await
+    {
+        Ok(network) => network,
+        Err((address, e)) => {
+            eprintln!("Cannot listen on {}: {}", address, e);
+            std::process::exit(1);
+        }
+    };
     println!("Local peer id: {:?}", local_peer_id);
 
-    // Start JSON-RPC server
-    rpc::start_rpc(chain.clone(), Arc::new(std::sync::Mutex::new(Token::new("Xelarius", "XZN", 42000000)))).await;
+    // Start JSON-RPC server in the background; it never returns
+    tokio::spawn(rpc::start_rpc(
+        config.rpc_addr,
+        service.clone(),
+        keystore,
+        net_tx.clone(),
+        sync_progress,
+    ));
 
-    // Start consensus, tx generation, and periodic printing tasks
-    tasks::start_tasks(chain, mempool, db, state, net_tx, net_rx).await;
+    // Start the block producer and the periodic chain printer; peer
+    // messages are handled in network.rs
+    tasks::start_tasks(
+        service,
+        net_tx,
+        tasks::Producer {
+            proposer: local_peer_id.to_string(),
+            params: genesis.params,
+            clock,
+        },
+    )
+    .await;
 }
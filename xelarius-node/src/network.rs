@@ -1,75 +1,507 @@
 // network.rs
-// Handles libp2p setup, swarm, and event loop
+// Handles libp2p setup, swarm, and event loop. Messages to publish arrive on
+// a channel as NetworkMessage and cross the wire as its bincode encoding.
+// Sync requests and responses travel through the same loop, but are handled
+// by the Syncer on its own thread (see sync.rs).
+//
+// Gossipsub forwards nothing on its own: each message from a peer is handed
+// to the ChainService first, and only the ones it accepts travel on. That
+// check takes the chain and state locks and may write to the database, so it
+// runs in a task of its own, one message at a time, and the verdict comes
+// back to the event loop on a channel. Message IDs are the hash of the
+// content, so the same block or transaction is handled once however many
+// peers relay it. Peers are scored on the messages they relay; invalid ones
+// cost them, and a peer whose score falls below BAN_SCORE is disconnected and
+// refused until this node restarts.
+//
+// Peers are found three ways: the static bootstrap list, mDNS on the local
+// network, and Kademlia, which learns peers' addresses from the ones already
+// connected. Every peer found is dialed, so gossipsub can reach it.
 
+use lazy_static::lazy_static;
 use libp2p::{
+    Multiaddr, PeerId, StreamProtocol, Transport as Libp2pTransport, TransportError,
+    allow_block_list::{self, BlockedPeers},
     core::upgrade,
-    gossipsub::{Behaviour as Gossipsub, Config as GossipsubConfig, Event as GossipsubEvent, IdentTopic, MessageAuthenticity},
-    identity,
-    noise::{NoiseConfig, X25519Spec, Keypair as NoiseKeypair},
-    swarm::{Swarm, SwarmBuilder},
-    tcp::{Transport, Config as TcpConfig},
-    yamux::YamuxConfig,
-    PeerId,
-    Transport as Libp2pTransport,
+    dns,
+    gossipsub::{
+        Behaviour as Gossipsub, ConfigBuilder as GossipsubConfigBuilder, Event as GossipsubEvent,
+        IdentTopic, MessageAcceptance, MessageAuthenticity, MessageId, PeerScoreParams,
+        PeerScoreThresholds, TopicScoreParams, ValidationMode,
+    },
+    identify, identity,
+    kad::{self, store::MemoryStore},
+    mdns,
+    multiaddr::Protocol,
+    noise,
+    request_response::{self, ProtocolSupport},
+    swarm::{
+        Config as SwarmConfig, NetworkBehaviour, Swarm, SwarmEvent, behaviour::toggle::Toggle,
+    },
+    tcp::{Config as TcpConfig, tokio::Transport},
+    yamux,
 };
-use tokio::sync::mpsc;
-use tracing::{info, error};
-use lazy_static::lazy_static;
 use prometheus::{IntGauge, Registry};
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+use xelarius_core::clock::Clock;
+use xelarius_core::encoding::sha256;
+use xelarius_core::message::{BLOCK_TOPIC, MAX_MESSAGE_BYTES, NetworkMessage, TX_TOPIC};
+use xelarius_core::sync::SyncRequest;
+
+use crate::chain::{ChainService, Verdict};
+use crate::sync::{
+    STATUS_INTERVAL, SYNC_PROTOCOL, SharedProgress, SyncBehaviour, SyncCommand, SyncEvent, Syncer,
+};
 
 lazy_static! {
-    static ref NETWORK_LATENCY: IntGauge = IntGauge::new("network_latency", "Network latency in ms").unwrap();
+    static ref NETWORK_LATENCY: IntGauge =
+        IntGauge::new("network_latency", "Network latency in ms").unwrap();
+}
+
+const IDENTIFY_PROTOCOL: &str = "/xelarius/id/1.0.0";
+// Our own Kademlia protocol, so the DHT never mixes with public IPFS nodes
+const KAD_PROTOCOL: &str = "/xelarius/kad/1.0.0";
+// How often to refresh the Kademlia routing table, and to redial the
+// bootstrap list while no peer is connected
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(30);
+// Each invalid message a peer relayed adds to a counter that decays by this
+// factor every second, halving in about 70 seconds. The peer's score falls by
+// the weight times the counter squared: three invalid messages in quick
+// succession pass gossipsub's graylist threshold of -80, after which its
+// messages are ignored, and seven pass BAN_SCORE.
+const INVALID_MESSAGE_WEIGHT: f64 = -10.0;
+const INVALID_MESSAGE_DECAY: f64 = 0.99;
+const BAN_SCORE: f64 = -400.0;
+// Messages waiting for a verdict; any beyond this are ignored
+const VALIDATION_QUEUE: usize = 1024;
+
+// A gossip message on its way to or from ChainService::handle_message
+struct Validation<T> {
+    id: MessageId,
+    source: PeerId,
+    payload: T,
+}
+
+// Where to listen and whom to ask for peers, from the command line
+pub struct NetworkConfig {
+    pub listen: Vec<Multiaddr>,
+    // Addresses may end in /p2p/<peer id>; without it the peer ID is learned
+    // once connected
+    pub bootstrap: Vec<Multiaddr>,
+    pub mdns: bool,
 }
 
-pub async fn setup_network() -> (mpsc::UnboundedSender<Vec<u8>>, mpsc::UnboundedReceiver<Vec<u8>>, PeerId) {
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: Gossipsub,
+    kademlia: kad::Behaviour<MemoryStore>,
+    identify: identify::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    sync: SyncBehaviour,
+    banned: allow_block_list::Behaviour<BlockedPeers>,
+}
+
+// Returns the sender for messages to publish and this node's peer ID.
+// Messages from peers go to `service`. Fails only if a listen address cannot
+// be used.
+pub async fn setup_network(
+    clock: Arc<dyn Clock>,
+    id_keys: identity::Keypair,
+    config: NetworkConfig,
+    service: ChainService,
+    sync_progress: SharedProgress,
+) -> Result<
+    (mpsc::UnboundedSender<NetworkMessage>, PeerId),
+    (Multiaddr, TransportError<std::io::Error>),
+> {
     info!("Setting up network...");
     let registry = Registry::new();
-    registry.register(Box::new(NETWORK_LATENCY.clone())).unwrap();
+    registry
+        .register(Box::new(NETWORK_LATENCY.clone()))
+        .unwrap();
 
-    let id_keys = identity::Keypair::generate_ed25519();
     let peer_id = PeerId::from(id_keys.public());
 
-    let noise_keys = NoiseKeypair::<X25519Spec>::new().into_authentic(&id_keys).unwrap();
-    let transport = Transport::new(TcpConfig::default())
+    // DNS so bootstrap addresses can name hosts, e.g. /dns4/node1/tcp/4000
+    let transport = dns::tokio::Transport::system(Transport::new(TcpConfig::default()))
+        .expect("system DNS configuration")
         .upgrade(upgrade::Version::V1)
-        .authenticate(NoiseConfig::xx(noise_keys).unwrap())
-        .multiplex(YamuxConfig::default())
+        .authenticate(noise::Config::new(&id_keys).unwrap())
+        .multiplex(yamux::Config::default())
         .boxed();
 
-    let gossipsub_config = GossipsubConfig::default();
-    let mut behaviour = Gossipsub::new(MessageAuthenticity::Signed(id_keys.clone()), gossipsub_config).unwrap();
-    let topic = IdentTopic::new("xelarius-blocks");
-    behaviour.subscribe(&topic).unwrap();
+    // Large enough for a full block
+    let gossipsub_config = GossipsubConfigBuilder::default()
+        .max_transmit_size(MAX_MESSAGE_BYTES)
+        .message_id_fn(|message| MessageId::from(sha256(&message.data).to_vec()))
+        .validation_mode(ValidationMode::Strict)
+        .validate_messages()
+        .build()
+        .unwrap();
+    let mut gossipsub: Gossipsub = Gossipsub::new(
+        MessageAuthenticity::Signed(id_keys.clone()),
+        gossipsub_config,
+    )
+    .unwrap();
+    let topics = [IdentTopic::new(BLOCK_TOPIC), IdentTopic::new(TX_TOPIC)];
+    let mut score_params = PeerScoreParams::default();
+    for topic in &topics {
+        gossipsub.subscribe(topic).unwrap();
+        score_params
+            .topics
+            .insert(topic.hash(), invalid_message_score());
+    }
+    gossipsub
+        .with_peer_score(score_params, PeerScoreThresholds::default())
+        .unwrap();
+
+    let mut kad_config = kad::Config::default();
+    kad_config.set_protocol_names(vec![StreamProtocol::new(KAD_PROTOCOL)]);
+    let mut kademlia = kad::Behaviour::with_config(peer_id, MemoryStore::new(peer_id), kad_config);
+    // Answer queries even without a confirmed public address, as devnet
+    // nodes never have one
+    kademlia.set_mode(Some(kad::Mode::Server));
 
-    let mut swarm = SwarmBuilder::with_executor(transport, behaviour, peer_id).build();
+    let identify = identify::Behaviour::new(identify::Config::new(
+        IDENTIFY_PROTOCOL.to_string(),
+        id_keys.public(),
+    ));
+    let mdns = if config.mdns {
+        Some(mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id).expect("mDNS socket"))
+    } else {
+        None
+    };
 
-    let (net_tx, mut net_rx) = mpsc::unbounded_channel();
+    let behaviour = Behaviour {
+        gossipsub,
+        kademlia,
+        identify,
+        mdns: mdns.into(),
+        sync: SyncBehaviour::new(
+            [(StreamProtocol::new(SYNC_PROTOCOL), ProtocolSupport::Full)],
+            request_response::Config::default(),
+        ),
+        banned: Default::default(),
+    };
+    let mut swarm = Swarm::new(
+        transport,
+        behaviour,
+        peer_id,
+        SwarmConfig::with_tokio_executor().with_idle_connection_timeout(Duration::from_secs(60)),
+    );
+
+    for address in &config.listen {
+        swarm
+            .listen_on(address.clone())
+            .map_err(|e| (address.clone(), e))?;
+    }
+    for address in &config.bootstrap {
+        dial_bootstrap(&mut swarm, address);
+    }
+
+    let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel::<NetworkMessage>();
+    let syncer = Syncer::new(service.clone(), sync_progress);
+    let (sync_tx, sync_events) = mpsc::unbounded_channel::<SyncEvent>();
+    let (sync_commands, mut sync_rx) = mpsc::unbounded_channel::<SyncCommand>();
+    thread::spawn(move || syncer.run(sync_events, sync_commands));
+
+    // Validates gossip messages in arrival order, so a block is handled
+    // after the parent that arrived before it
+    let (validate_tx, mut validate_rx) =
+        mpsc::channel::<Validation<NetworkMessage>>(VALIDATION_QUEUE);
+    let (verdict_tx, mut verdict_rx) = mpsc::unbounded_channel::<Validation<Verdict>>();
+    tokio::spawn(async move {
+        while let Some(Validation {
+            id,
+            source,
+            payload,
+        }) = validate_rx.recv().await
+        {
+            let service = service.clone();
+            let Ok(verdict) =
+                tokio::task::spawn_blocking(move || service.handle_message(payload)).await
+            else {
+                error!("Validating message {} panicked", id);
+                continue;
+            };
+            let validated = Validation {
+                id,
+                source,
+                payload: verdict,
+            };
+            if verdict_tx.send(validated).is_err() {
+                return;
+            }
+        }
+    });
 
     use futures::StreamExt;
     tokio::spawn(async move {
+        let mut bootstrap = tokio::time::interval(BOOTSTRAP_INTERVAL);
+        let mut status = tokio::time::interval(STATUS_INTERVAL);
         loop {
             tokio::select! {
-                Some(msg) = net_rx.recv() => {
-                    let start = SystemTime::now();
-                    if let Err(e) = swarm.behaviour_mut().publish(topic.clone(), msg) {
-                        error!("Failed to publish message: {:?}", e);
+                Some(msg) = outbound_rx.recv() => {
+                    let start = clock.now_millis();
+                    let kind = msg.kind();
+                    let topic = IdentTopic::new(msg.topic());
+                    let data = match msg.encode() {
+                        Ok(data) => data,
+                        Err(e) => {
+                            error!("Cannot encode {} for publishing: {}", kind, e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic, data) {
+                        error!("Failed to publish {}: {:?}", kind, e);
                     } else {
-                        let latency = SystemTime::now().duration_since(start).unwrap().as_millis() as i64;
+                        let latency = clock.now_millis().saturating_sub(start) as i64;
                         NETWORK_LATENCY.set(latency);
-                        info!("Message published successfully. Latency: {} ms", latency);
+                        info!("Published {}. Latency: {} ms", kind, latency);
                     }
                 }
-                event = swarm.next() => {
-                    if let Some(libp2p::swarm::SwarmEvent::Behaviour(GossipsubEvent::Message { message, .. })) = event {
-                        info!("Received gossipsub message: {:?}", message.data);
-                        // TODO: Deserialize and process block/tx
+                _ = bootstrap.tick() => {
+                    if swarm.connected_peers().next().is_none() {
+                        for address in &config.bootstrap {
+                            dial_bootstrap(&mut swarm, address);
+                        }
                     }
+                    // Fails only while the routing table is empty
+                    let _ = swarm.behaviour_mut().kademlia.bootstrap();
+                }
+                _ = status.tick() => {
+                    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                    for peer in peers {
+                        swarm.behaviour_mut().sync.send_request(&peer, SyncRequest::Status);
+                    }
+                }
+                Some(validated) = verdict_rx.recv() => report(&mut swarm, validated),
+                Some(command) = sync_rx.recv() => match command {
+                    SyncCommand::Request(peer, request) => {
+                        swarm.behaviour_mut().sync.send_request(&peer, request);
+                    }
+                    // Fails only if the peer gave up waiting
+                    SyncCommand::Respond(channel, response) => {
+                        let _ = swarm.behaviour_mut().sync.send_response(channel, response);
+                    }
+                },
+                event = swarm.select_next_some() => {
+                    handle_swarm_event(&mut swarm, event, peer_id, &validate_tx, &sync_tx)
                 }
             }
         }
     });
 
     info!("Network setup complete.");
-    (net_tx, net_rx, peer_id)
+    Ok((outbound_tx, peer_id))
+}
+
+// Scores only invalid messages. Gossipsub's rewards for time in the mesh and
+// message deliveries stay off: on a quiet devnet they would penalize honest
+// peers for a lack of traffic, or let a long-connected peer outweigh its
+// invalid messages.
+fn invalid_message_score() -> TopicScoreParams {
+    TopicScoreParams {
+        topic_weight: 1.0,
+        time_in_mesh_weight: 0.0,
+        first_message_deliveries_weight: 0.0,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        invalid_message_deliveries_weight: INVALID_MESSAGE_WEIGHT,
+        invalid_message_deliveries_decay: INVALID_MESSAGE_DECAY,
+        ..TopicScoreParams::default()
+    }
+}
+
+// Passes a verdict on to gossipsub, which forwards accepted messages and
+// scores the peer that relayed rejected ones
+fn report(swarm: &mut Swarm<Behaviour>, validated: Validation<Verdict>) {
+    let Validation {
+        id,
+        source,
+        payload: verdict,
+    } = validated;
+    let acceptance = match verdict {
+        Verdict::Accept => MessageAcceptance::Accept,
+        Verdict::Ignore => MessageAcceptance::Ignore,
+        Verdict::Reject => MessageAcceptance::Reject,
+    };
+    let gossipsub = &mut swarm.behaviour_mut().gossipsub;
+    let _ = gossipsub.report_message_validation_result(&id, &source, acceptance);
+    if let Some(score) = gossipsub
+        .peer_score(&source)
+        .filter(|&score| score < BAN_SCORE)
+    {
+        ban(swarm, source, score);
+    }
+}
+
+fn ban(swarm: &mut Swarm<Behaviour>, peer: PeerId, score: f64) {
+    warn!("Banning {}: score {:.0}", peer, score);
+    swarm.behaviour_mut().kademlia.remove_peer(&peer);
+    swarm.behaviour_mut().banned.block_peer(peer);
+}
+
+fn dial_bootstrap(swarm: &mut Swarm<Behaviour>, address: &Multiaddr) {
+    if let Some(Protocol::P2p(peer)) = address.iter().last() {
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&peer, address.clone());
+    }
+    if let Err(e) = swarm.dial(address.clone()) {
+        warn!("Cannot dial bootstrap peer {}: {}", address, e);
+    }
+}
+
+fn handle_swarm_event<E>(
+    swarm: &mut Swarm<Behaviour>,
+    event: SwarmEvent<BehaviourEvent, E>,
+    local_peer_id: PeerId,
+    validate: &mpsc::Sender<Validation<NetworkMessage>>,
+    sync: &mpsc::UnboundedSender<SyncEvent>,
+) {
+    match event {
+        // Printed so operators can hand it to other nodes as --bootstrap
+        SwarmEvent::NewListenAddr { address, .. } => {
+            println!("Listening on {}/p2p/{}", address, local_peer_id);
+        }
+        // A new peer may be ahead of us
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            endpoint,
+            num_established,
+            ..
+        } => {
+            info!(
+                "Connected to {} at {}",
+                peer_id,
+                endpoint.get_remote_address()
+            );
+            if num_established.get() == 1 {
+                swarm
+                    .behaviour_mut()
+                    .sync
+                    .send_request(&peer_id, SyncRequest::Status);
+            }
+        }
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established,
+            ..
+        } => {
+            info!("Disconnected from {}", peer_id);
+            if num_established == 0 {
+                let _ = sync.send(SyncEvent::PeerGone(peer_id));
+            }
+        }
+        SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+            warn!("Cannot connect to {:?}: {}", peer_id, error);
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+            for (peer, address) in found {
+                info!("Found {} at {} via mDNS", peer, address);
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peer, address.clone());
+                if !swarm.is_connected(&peer) {
+                    let _ = swarm.dial(address);
+                }
+            }
+        }
+        // Tell Kademlia where a connected peer listens, so it can pass the
+        // address on to others
+        SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+            peer_id,
+            info,
+        })) => {
+            for address in info.listen_addrs {
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peer_id, address);
+            }
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::RoutingUpdated {
+            peer,
+            is_new_peer: true,
+            ..
+        })) if !swarm.is_connected(&peer) => {
+            info!("Found {} via Kademlia", peer);
+            let _ = swarm.dial(peer);
+        }
+        // Forwarded to other peers only once accepted
+        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(GossipsubEvent::Message {
+            propagation_source,
+            message_id,
+            message,
+        })) => {
+            let verdict = match NetworkMessage::decode(&message.data) {
+                Ok(msg) if message.topic.as_str() == msg.topic() => {
+                    let pending = Validation {
+                        id: message_id.clone(),
+                        source: propagation_source,
+                        payload: msg,
+                    };
+                    match validate.try_send(pending) {
+                        // The verdict arrives on the event loop's channel
+                        Ok(()) => return,
+                        Err(_) => {
+                            warn!("Validation queue full, ignoring {}", message_id);
+                            Verdict::Ignore
+                        }
+                    }
+                }
+                Ok(msg) => {
+                    warn!(
+                        "{} from {} on the wrong topic {}",
+                        msg.kind(),
+                        propagation_source,
+                        message.topic
+                    );
+                    Verdict::Reject
+                }
+                Err(e) => {
+                    warn!("Undecodable message from {}: {}", propagation_source, e);
+                    Verdict::Reject
+                }
+            };
+            report(
+                swarm,
+                Validation {
+                    id: message_id,
+                    source: propagation_source,
+                    payload: verdict,
+                },
+            );
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Sync(request_response::Event::Message {
+            peer,
+            message,
+        })) => {
+            let event = match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => SyncEvent::Request { request, channel },
+                request_response::Message::Response { response, .. } => {
+                    SyncEvent::Response { peer, response }
+                }
+            };
+            let _ = sync.send(event);
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Sync(request_response::Event::OutboundFailure {
+            peer,
+            error,
+            ..
+        })) => {
+            warn!("Sync request to {} failed: {}", peer, error);
+            let _ = sync.send(SyncEvent::PeerGone(peer));
+        }
+        _ => {}
+    }
 }
@@ -0,0 +1,126 @@
+// node_key.rs
+// The node's libp2p identity. Its ed25519 secret is kept hex-encoded in a
+// file, by default `node_key` in the data directory, so the peer ID survives
+// restarts and can be handed out in bootstrap lists and allowlists.
+
+use libp2p::identity::{Keypair, ed25519};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+pub const NODE_KEY_FILE: &str = "node_key";
+
+#[derive(Debug)]
+pub enum NodeKeyError {
+    Read(PathBuf, io::Error),
+    Write(PathBuf, io::Error),
+    Invalid(PathBuf),
+}
+
+impl fmt::Display for NodeKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeKeyError::Read(path, e) => {
+                write!(f, "cannot read node key {}: {}", path.display(), e)
+            }
+            NodeKeyError::Write(path, e) => {
+                write!(f, "cannot write node key {}: {}", path.display(), e)
+            }
+            NodeKeyError::Invalid(path) => write!(
+                f,
+                "{} does not hold a hex-encoded 32-byte ed25519 secret key",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NodeKeyError {}
+
+// Loads the key at `path`. A missing file is an error when the path was
+// given explicitly; otherwise a new key is generated and saved there.
+pub fn load_or_create(path: &Path, create: bool) -> Result<Keypair, NodeKeyError> {
+    match fs::read_to_string(path).map(Zeroizing::new) {
+        Ok(contents) => decode(&contents).ok_or_else(|| NodeKeyError::Invalid(path.to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound && create => {
+            let secret = ed25519::SecretKey::generate();
+            let encoded = Zeroizing::new(hex::encode(secret.as_ref()));
+            write_private(path, encoded.as_bytes())
+                .map_err(|e| NodeKeyError::Write(path.to_owned(), e))?;
+            Ok(ed25519::Keypair::from(secret).into())
+        }
+        Err(e) => Err(NodeKeyError::Read(path.to_owned(), e)),
+    }
+}
+
+fn decode(contents: &str) -> Option<Keypair> {
+    let mut bytes = Zeroizing::new(hex::decode(contents.trim()).ok()?);
+    let secret = ed25519::SecretKey::try_from_bytes(bytes.as_mut_slice()).ok()?;
+    Some(ed25519::Keypair::from(secret).into())
+}
+
+// Readable only by the owner on unix, and never left half-written
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    {
+        let mut file = options.open(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_created_key_is_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").join(NODE_KEY_FILE);
+        let created = load_or_create(&path, true).unwrap();
+        let reloaded = load_or_create(&path, false).unwrap();
+        assert_eq!(
+            created.public().to_peer_id(),
+            reloaded.public().to_peer_id()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_bad_or_missing_keys_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(NODE_KEY_FILE);
+        // Given explicitly, a missing file is not created
+        assert!(matches!(
+            load_or_create(&path, false),
+            Err(NodeKeyError::Read(..))
+        ));
+        assert!(!path.exists());
+
+        for contents in ["not hex", "abcd", ""] {
+            fs::write(&path, contents).unwrap();
+            assert!(matches!(
+                load_or_create(&path, true),
+                Err(NodeKeyError::Invalid(_))
+            ));
+        }
+    }
+}
@@ -1,38 +1,202 @@
 // rpc.rs
 // Handles JSON-RPC server and request/response logic
 
+use serde_json::json;
 use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::io::AsyncWriteExt;
-use xelarius_core::{Blockchain, Wallet, Token};
-use serde_json::json;
-use tracing::{info, error};
-use lazy_static::lazy_static;
+use tokio::sync::mpsc;
+use xelarius_core::encoding::to_hex;
+use xelarius_core::keystore::{Keystore, KeystoreError};
+use xelarius_core::message::NetworkMessage;
+use xelarius_core::receipt::{IndexedReceipt, TxStatus};
+use xelarius_core::{AccountProof, Address, Blockchain, StateStore, Transaction, TxHash, Wallet};
+
+use crate::chain::ChainService;
+use crate::sync::SharedProgress;
+
+// Wallets made by create_wallet are kept in this directory under the data
+// directory
+pub const KEYSTORE_DIR: &str = "keystore";
 
-pub async fn start_rpc(chain: Arc<std::sync::Mutex<Blockchain>>, token: Arc<std::sync::Mutex<Token>>) {
-    let listener = TcpListener::bind("127.0.0.1:8545").await.expect("bind");
+// Standard JSON-RPC codes, plus server-defined codes for rejected
+// transactions and state this node cannot serve
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const TX_REJECTED: i64 = -32000;
+const STATE_UNAVAILABLE: i64 = -32001;
+
+pub async fn start_rpc(
+    address: String,
+    service: ChainService,
+    keystore: Arc<Keystore>,
+    net_tx: mpsc::UnboundedSender<NetworkMessage>,
+    sync_progress: SharedProgress,
+) {
+    let listener = TcpListener::bind(&address).await.expect("bind");
     loop {
         let (mut socket, _) = listener.accept().await.expect("accept");
-        let chain = chain.clone();
-        let token = token.clone();
+        let service = service.clone();
+        let ChainService {
+            chain, state, db, ..
+        } = service.clone();
+        let keystore = keystore.clone();
+        let net_tx = net_tx.clone();
+        let sync_progress = sync_progress.clone();
         tokio::spawn(async move {
-            let request = "dummy_request"; // Replace with actual request parsing
-            match request {
+            let mut buf = vec![0u8; 64 * 1024];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let request: serde_json::Value = serde_json::from_slice(&buf[..n]).unwrap_or_default();
+            match request["method"].as_str().unwrap_or_default() {
                 "create_wallet" => {
-                    let wallet = Wallet::new("ckelley.eth", "private_key_stub");
-                    let resp = json!({"wallet": wallet});
+                    // The key is only ever written to the keystore, encrypted
+                    // under the caller's passphrase; never return key material
+                    // over RPC
+                    let params = &request["params"];
+                    let resp = match (params["name"].as_str(), params["passphrase"].as_str()) {
+                        (Some(name), Some(passphrase)) => {
+                            let (name, passphrase) = (name.to_string(), passphrase.to_string());
+                            // Deriving the encryption key is deliberately slow
+                            let saved = tokio::task::spawn_blocking(move || {
+                                let wallet = Wallet::generate();
+                                keystore
+                                    .save(&name, &wallet, &passphrase)
+                                    .map(|()| (name, wallet.address))
+                            })
+                            .await
+                            .expect("keystore task panicked");
+                            match saved {
+                                Ok((name, address)) => json!({
+                                    "jsonrpc": "2.0",
+                                    "result": {"name": name, "address": address.to_string()},
+                                    "id": 1
+                                }),
+                                Err(e) => {
+                                    let code = match e {
+                                        KeystoreError::InvalidName(_)
+                                        | KeystoreError::AlreadyExists(_) => INVALID_PARAMS,
+                                        _ => INTERNAL_ERROR,
+                                    };
+                                    json!({
+                                        "jsonrpc": "2.0",
+                                        "error": {"code": code, "message": e.to_string()},
+                                        "id": 1
+                                    })
+                                }
+                            }
+                        }
+                        _ => json!({
+                            "jsonrpc": "2.0",
+                            "error": {
+                                "code": INVALID_PARAMS,
+                                "message": "params must hold a name and a passphrase",
+                            },
+                            "id": 1
+                        }),
+                    };
                     let _ = socket.write_all(resp.to_string().as_bytes()).await;
                 }
-                "mint_token" => {
-                    let mut token_data = {
-                        let mut token = token.lock().unwrap();
-                        token.transfer("genesis", "ckelley.eth", 1000).unwrap();
-                        token.clone()
+                "get_balance" => {
+                    let resp = match request["params"]["address"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .parse::<Address>()
+                    {
+                        Ok(address) => {
+                            // Lock order matches the consensus loop: chain, then state
+                            let chain = chain.lock().unwrap();
+                            let state = state.lock().unwrap();
+                            json!({
+                                "jsonrpc": "2.0",
+                                "result": {
+                                    "address": address.to_string(),
+                                    "balance": state.balance(&address),
+                                    "nonce": state.nonce(&address),
+                                    "block_index": chain.tip().header.index,
+                                },
+                                "id": 1
+                            })
+                        }
+                        Err(e) => json!({
+                            "jsonrpc": "2.0",
+                            "error": {"code": INVALID_PARAMS, "message": e.to_string()},
+                            "id": 1
+                        }),
+                    };
+                    let _ = socket.write_all(resp.to_string().as_bytes()).await;
+                }
+                "get_supply" => {
+                    let (block_index, supply) = {
+                        let chain = chain.lock().unwrap();
+                        let supply = state.lock().unwrap().supply();
+                        (chain.tip().header.index, supply)
+                    };
+                    // Amounts are base units; 1 XZN = 10^8
+                    let resp = match supply {
+                        Some(supply) => json!({
+                            "jsonrpc": "2.0",
+                            "result": {
+                                "max_supply": supply.max,
+                                "total_supply": supply.total,
+                                "burned": supply.burned,
+                                "circulating_supply": supply.circulating,
+                                "block_index": block_index,
+                            },
+                            "id": 1
+                        }),
+                        None => json!({
+                            "jsonrpc": "2.0",
+                            "error": {"code": INTERNAL_ERROR, "message": "supply overflows u64"},
+                            "id": 1
+                        }),
                     };
-                    let resp = json!({"status": "success", "token": token_data});
                     let _ = socket.write_all(resp.to_string().as_bytes()).await;
                 }
                 "submit_tx" => {
+                    // Tell the client why a transaction was turned away;
+                    // admitted ones are passed on to peers. The checks are
+                    // the ones a transaction from a peer goes through.
+                    let resp = match serde_json::from_value::<Transaction>(
+                        request["params"].clone(),
+                    ) {
+                        Ok(tx) => match service.admit_tx(tx.clone()) {
+                            Ok(hash) => {
+                                let _ = net_tx.send(NetworkMessage::NewTx(tx));
+                                json!({"jsonrpc":"2.0","result":hash.to_string(),"id":1})
+                            }
+                            Err(e) => json!({
+                                "jsonrpc": "2.0",
+                                "error": {"code": TX_REJECTED, "message": e.to_string(), "data": e},
+                                "id": 1
+                            }),
+                        },
+                        Err(e) => json!({
+                            "jsonrpc": "2.0",
+                            "error": {"code": INVALID_PARAMS, "message": e.to_string()},
+                            "id": 1
+                        }),
+                    };
+                    let _ = socket.write_all(resp.to_string().as_bytes()).await;
+                }
+                "get_receipt" => {
+                    // Unknown or still pending transactions get a null result
+                    let resp = match parse_tx_hash(
+                        request["params"]["tx_hash"].as_str().unwrap_or_default(),
+                    ) {
+                        Ok(hash) => json!({
+                            "jsonrpc": "2.0",
+                            "result": db.get_receipt(&hash).as_ref().map(receipt_json),
+                            "id": 1
+                        }),
+                        Err(message) => json!({
+                            "jsonrpc": "2.0",
+                            "error": {"code": INVALID_PARAMS, "message": message},
+                            "id": 1
+                        }),
+                    };
+                    let _ = socket.write_all(resp.to_string().as_bytes()).await;
+                }
+                "chain_length" => {
                     let len = {
                         let chain = chain.lock().unwrap();
                         chain.chain.len()
@@ -40,6 +204,48 @@ pub async fn start_rpc(chain: Arc<std::sync::Mutex<Blockchain>>, token: Arc<std:
                     let resp = json!({"jsonrpc":"2.0","result":len,"id":1});
                     let _ = socket.write_all(resp.to_string().as_bytes()).await;
                 }
+                "sync_status" => {
+                    let progress = *sync_progress.lock().unwrap();
+                    let resp = json!({
+                        "jsonrpc": "2.0",
+                        "result": {
+                            "syncing": progress.is_syncing(),
+                            "height": progress.height,
+                            "target": progress.target,
+                            "peers": progress.peers,
+                            "requests": progress.requests,
+                        },
+                        "id": 1
+                    });
+                    let _ = socket.write_all(resp.to_string().as_bytes()).await;
+                }
+                "get_account_proof" => {
+                    let resp = match request["params"]["address"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .parse::<Address>()
+                    {
+                        Ok(address) => {
+                            // Lock order matches the consensus loop: chain, then state
+                            let chain = chain.lock().unwrap();
+                            let state = state.lock().unwrap();
+                            match prove_account(&chain, &state, &address, &request["params"]) {
+                                Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": 1}),
+                                Err(message) => json!({
+                                    "jsonrpc": "2.0",
+                                    "error": {"code": STATE_UNAVAILABLE, "message": message},
+                                    "id": 1
+                                }),
+                            }
+                        }
+                        Err(e) => json!({
+                            "jsonrpc": "2.0",
+                            "error": {"code": INVALID_PARAMS, "message": e.to_string()},
+                            "id": 1
+                        }),
+                    };
+                    let _ = socket.write_all(resp.to_string().as_bytes()).await;
+                }
                 _ => {
                     let resp = json!({"error": "unknown method"});
                     let _ = socket.write_all(resp.to_string().as_bytes()).await;
@@ -48,3 +254,87 @@ pub async fn start_rpc(chain: Arc<std::sync::Mutex<Blockchain>>, token: Arc<std:
         });
     }
 }
+
+// Proves `address` against the state after the canonical block at
+// `params.height`, or the latest one committing to `params.state_root`; the
+// tip if neither is given. Past state is rebuilt from the tip with the
+// chain's undo logs.
+fn prove_account(
+    chain: &Blockchain,
+    state: &StateStore,
+    address: &Address,
+    params: &serde_json::Value,
+) -> Result<serde_json::Value, String> {
+    let height = if let Some(height) = params["height"].as_u64() {
+        height
+    } else if let Some(root) = params["state_root"].as_str() {
+        chain
+            .chain
+            .iter()
+            .rposition(|block| block.header.state_root == root)
+            .ok_or_else(|| format!("state root {} is not on the canonical chain", root))?
+            as u64
+    } else {
+        chain.tip().header.index
+    };
+    let header = &chain
+        .chain
+        .get(height as usize)
+        .ok_or_else(|| format!("no canonical block at height {}", height))?
+        .header;
+    let proof = if height == chain.tip().header.index {
+        state.prove(address)
+    } else {
+        chain
+            .state_at(state, height)
+            .ok_or_else(|| format!("no state at height {}", height))?
+            .prove(address)
+    };
+    Ok(account_proof_json(&proof, header.index, &header.state_root))
+}
+
+// Hashes are hex so light clients can check the proof without our types
+fn account_proof_json(
+    proof: &AccountProof,
+    block_index: u64,
+    state_root: &str,
+) -> serde_json::Value {
+    json!({
+        "address": proof.address.to_string(),
+        "balance": proof.balance,
+        "nonce": proof.nonce,
+        "block_index": block_index,
+        "state_root": state_root,
+        "proof": {
+            "leaf": proof.proof.leaf.map(|(key, value)| json!({
+                "key": to_hex(&key),
+                "value": to_hex(&value),
+            })),
+            "siblings": proof.proof.siblings.iter().map(|s| to_hex(s)).collect::<Vec<_>>(),
+        },
+    })
+}
+
+fn parse_tx_hash(hex: &str) -> Result<TxHash, String> {
+    let bytes = hex::decode(hex).map_err(|e| format!("invalid transaction hash: {}", e))?;
+    let len = bytes.len();
+    <[u8; 32]>::try_from(bytes)
+        .map(TxHash)
+        .map_err(|_| format!("transaction hash must hold 32 bytes, got {}", len))
+}
+
+fn receipt_json(indexed: &IndexedReceipt) -> serde_json::Value {
+    let receipt = &indexed.receipt;
+    json!({
+        "tx_hash": receipt.tx_hash.to_string(),
+        "block_index": indexed.block_index,
+        "position": indexed.position,
+        "status": match receipt.status {
+            TxStatus::Success => "success",
+            TxStatus::Failed => "failed",
+        },
+        "failure_reason": receipt.failure_reason,
+        "fee_paid": receipt.fee_paid,
+        "events": receipt.events,
+    })
+}
@@ -0,0 +1,168 @@
+// sync.rs
+// Fetches the blocks this node missed from its peers and answers their
+// requests for ours, over a request-response protocol next to gossipsub. The
+// bookkeeping is xelarius_core::sync::SyncManager; this wires it to the chain.
+//
+// Answering a request takes the chain lock and importing a block also the
+// state lock and a database write, so the Syncer runs on a thread of its own.
+// The swarm's event loop passes it SyncEvents and carries out the
+// SyncCommands it sends back.
+
+use libp2p::PeerId;
+use libp2p::request_response::{ResponseChannel, cbor};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use xelarius_core::Block;
+use xelarius_core::sync::{SyncManager, SyncProgress, SyncRequest, SyncResponse, respond};
+
+use crate::chain::ChainService;
+
+pub const SYNC_PROTOCOL: &str = "/xelarius/sync/1.0.0";
+// How often connected peers are asked for their status, which is how this
+// node learns it has fallen behind
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+pub type SyncBehaviour = cbor::Behaviour<SyncRequest, SyncResponse>;
+// Read by the sync_status RPC
+pub type SharedProgress = Arc<Mutex<SyncProgress>>;
+
+// What the event loop hands the Syncer
+pub enum SyncEvent {
+    Request {
+        request: SyncRequest,
+        channel: ResponseChannel<SyncResponse>,
+    },
+    Response {
+        peer: PeerId,
+        response: SyncResponse,
+    },
+    PeerGone(PeerId),
+}
+
+// What the Syncer asks the event loop to send
+pub enum SyncCommand {
+    Request(PeerId, SyncRequest),
+    Respond(ResponseChannel<SyncResponse>, SyncResponse),
+}
+
+pub struct Syncer {
+    service: ChainService,
+    manager: SyncManager<PeerId>,
+    progress: SharedProgress,
+}
+
+impl Syncer {
+    pub fn new(service: ChainService, progress: SharedProgress) -> Self {
+        let manager = {
+            let chain = service.chain.lock().unwrap();
+            SyncManager::new(&chain.chain[0].hash, chain.tip().header.index)
+        };
+        Syncer {
+            service,
+            manager,
+            progress,
+        }
+    }
+
+    // Handles events until the event loop goes away. After each one, sends
+    // whatever requests are due.
+    pub fn run(
+        mut self,
+        mut events: mpsc::UnboundedReceiver<SyncEvent>,
+        commands: mpsc::UnboundedSender<SyncCommand>,
+    ) {
+        while let Some(event) = events.blocking_recv() {
+            let command = match event {
+                SyncEvent::Request { request, channel } => {
+                    Some(SyncCommand::Respond(channel, self.respond(&request)))
+                }
+                SyncEvent::Response { peer, response } => {
+                    self.response_received(peer, response);
+                    None
+                }
+                SyncEvent::PeerGone(peer) => {
+                    self.peer_gone(&peer);
+                    None
+                }
+            };
+            let requests = self
+                .poll()
+                .into_iter()
+                .map(|(peer, request)| SyncCommand::Request(peer, request));
+            for command in command.into_iter().chain(requests) {
+                if commands.send(command).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn respond(&self, request: &SyncRequest) -> SyncResponse {
+        respond(&self.service.chain.lock().unwrap(), request)
+    }
+
+    fn response_received(&mut self, peer: PeerId, response: SyncResponse) {
+        let chain = self.service.chain.lock().unwrap();
+        if let Err(e) = self
+            .manager
+            .response_received(peer, response, |hash| chain.tree.contains(hash))
+        {
+            warn!("Ignoring {} for sync: {}", peer, e);
+        }
+    }
+
+    fn peer_gone(&mut self, peer: &PeerId) {
+        self.manager.peer_gone(peer);
+    }
+
+    // Imports whatever has arrived in order, then returns the requests to
+    // send next
+    fn poll(&mut self) -> Vec<(PeerId, SyncRequest)> {
+        let before = *self.progress.lock().unwrap();
+        while let Some((peer, blocks)) = self.manager.next_batch() {
+            if !self.import(peer, blocks) {
+                break;
+            }
+        }
+        let height = self.service.chain.lock().unwrap().tip().header.index;
+        let requests = self.manager.next_requests(height);
+        let progress = self.manager.progress(height);
+        if progress.is_syncing() && progress.height != before.height {
+            info!(
+                "Syncing: block {} of {} from {} peers",
+                progress.height, progress.target, progress.peers
+            );
+        } else if before.is_syncing() && !progress.is_syncing() {
+            info!("Synced to block {}", progress.height);
+        }
+        *self.progress.lock().unwrap() = progress;
+        requests
+    }
+
+    // False if the batch stopped short and everything after it was dropped
+    fn import(&mut self, peer: PeerId, blocks: Vec<Block>) -> bool {
+        for block in blocks {
+            let height = block.header.index;
+            let attached = {
+                let chain = self.service.chain.lock().unwrap();
+                chain.tree.contains(&block.header.previous_hash)
+            };
+            if !attached {
+                info!(
+                    "Chain of {} forked from ours below block {}, looking for the fork point",
+                    peer, height
+                );
+                self.manager.forked(peer, height);
+                return false;
+            }
+            if let Err(e) = self.service.import_block(block) {
+                warn!("Invalid block {} from {}: {}", height, peer, e);
+                self.manager.invalid(&peer, height);
+                return false;
+            }
+        }
+        true
+    }
+}
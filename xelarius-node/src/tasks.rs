@@ -1,78 +1,91 @@
 // tasks.rs
-// Handles consensus loop, tx generation, and periodic printing
+// Handles consensus loop and periodic printing. Messages from peers are
+// handled in network.rs, which needs each one's verdict.
 
+use lazy_static::lazy_static;
+use prometheus::{IntCounter, IntGauge, Registry};
 use std::sync::Arc;
+use std::thread;
 use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
-use xelarius_core::{Blockchain, Mempool, PersistentChain, StateStore, Transaction};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tracing::{info, error};
-use prometheus::{IntCounter, IntGauge, Registry};
-use lazy_static::lazy_static;
+use tokio::time::{Duration, sleep};
+use tracing::{error, info, warn};
+use xelarius_core::clock::Clock;
+use xelarius_core::genesis::ProtocolParams;
+use xelarius_core::message::NetworkMessage;
+
+use crate::chain::ChainService;
 
 lazy_static! {
-    static ref BLOCK_PRODUCTION_RATE: IntCounter = IntCounter::new("block_production_rate", "Rate of block production").unwrap();
-    static ref NETWORK_LATENCY: IntGauge = IntGauge::new("network_latency", "Network latency in ms").unwrap();
+    static ref BLOCK_PRODUCTION_RATE: IntCounter =
+        IntCounter::new("block_production_rate", "Rate of block production").unwrap();
+    static ref NETWORK_LATENCY: IntGauge =
+        IntGauge::new("network_latency", "Network latency in ms").unwrap();
+}
+
+// Who signs the blocks this node produces, the chain spec's limits on them
+// and the clock that times production. Timestamps come from the chain's
+// clock, which is the same one.
+pub struct Producer {
+    pub proposer: String,
+    pub params: ProtocolParams,
+    pub clock: Arc<dyn Clock>,
 }
 
 pub async fn start_tasks(
-    chain: Arc<std::sync::Mutex<Blockchain>>,
-    mempool: Arc<Mempool>,
-    db: PersistentChain,
-    state: Arc<std::sync::Mutex<StateStore>>,
-    _net_tx: mpsc::UnboundedSender<Vec<u8>>,
-    _net_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    service: ChainService,
+    net_tx: mpsc::UnboundedSender<NetworkMessage>,
+    producer: Producer,
 ) {
+    let Producer {
+        proposer,
+        params,
+        clock,
+    } = producer;
     let registry = Registry::new();
-    registry.register(Box::new(BLOCK_PRODUCTION_RATE.clone())).unwrap();
-    registry.register(Box::new(NETWORK_LATENCY.clone())).unwrap();
+    registry
+        .register(Box::new(BLOCK_PRODUCTION_RATE.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(NETWORK_LATENCY.clone()))
+        .unwrap();
 
-    // Consensus loop: produce a block every 5 seconds if mempool has txs
-    tokio::spawn(async move {
+    // Consensus loop: every block_time_secs, produce a block from as many
+    // mempool txs as fit in max_block_bytes and publish it. Each turn holds
+    // the chain and state locks across a database write and sleeps on the
+    // clock, so it runs on a thread of its own rather than the runtime.
+    let consensus = service.clone();
+    let block_time_millis = params.block_time_secs.saturating_mul(1000);
+    thread::spawn(move || {
+        let mut next = clock.now_millis();
         loop {
-            let txs = mempool.drain();
-            if !txs.is_empty() {
-                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-                let valid_txs = {
-                    let mut state = state.lock().unwrap();
-                    txs.into_iter().filter(|tx| state.apply_tx(tx)).collect::<Vec<_>>()
-                };
-                let success = {
-                    let mut chain = chain.lock().unwrap();
-                    chain.add_block(valid_txs.clone(), now)
-                };
-                if success {
-                    db.store_block(&chain.lock().unwrap().chain.last().unwrap()).unwrap();
-                    BLOCK_PRODUCTION_RATE.inc();
-                    info!("Block produced at {}", now);
-                } else {
-                    error!("Failed to produce block at {}", now);
+            let production = consensus.produce(&proposer);
+            for tx in production.expired {
+                warn!("Dropping transaction {}: waited too long", tx.hash());
+            }
+            for (tx, e) in production.dropped {
+                warn!("Dropping transaction {}: {}", tx.hash(), e);
+            }
+            if let Some((block, stored)) = production.block {
+                let now = block.header.timestamp;
+                match stored {
+                    Ok(()) => {
+                        BLOCK_PRODUCTION_RATE.inc();
+                        info!("Block produced at {}", now);
+                        let _ = net_tx.send(NetworkMessage::NewBlock(block));
+                    }
+                    Err(e) => error!(
+                        "Failed to produce block at {}, discarding its state changes: {}",
+                        now, e
+                    ),
                 }
             }
-            sleep(Duration::from_secs(5)).await;
-        }
-    });
-
-    // Dummy tx generator: add a tx every 10 seconds
-    tokio::spawn(async move {
-        let mut nonce = 0;
-        loop {
-            let tx = Transaction {
-                from: "genesis".into(),
-                to: "test".into(),
-                amount: 1,
-                nonce,
-                signature: Some("dummy_sig".into()),
-            };
-            mempool.add_tx(tx.clone());
-            nonce += 1;
-            info!("Transaction added: {:?}", tx);
-            sleep(Duration::from_secs(10)).await;
+            next = next.saturating_add(block_time_millis);
+            clock.sleep_until(next);
         }
     });
 
     // Print chain state every 15 seconds
-    let chain_print = chain.clone();
+    let chain_print = service.chain.clone();
     tokio::spawn(async move {
         loop {
             let chain_str = {
@@ -85,5 +98,7 @@ pub async fn start_tasks(
     });
 
     // Keep main alive
-    loop { sleep(Duration::from_secs(60)).await; }
+    loop {
+        sleep(Duration::from_secs(60)).await;
+    }
 }